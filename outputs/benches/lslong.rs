@@ -0,0 +1,68 @@
+//! Times `lslong` over synthetic directories of 100k entries, statting sequentially and with
+//! `--parallel`. Run with `cargo bench --bench lslong`; set `LSLONG_BENCH_ENTRIES` to change
+//! the directory size.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const DEFAULT_ENTRIES: usize = 100_000;
+const RUNS: usize = 5;
+
+/// Fills `dir` with `entries` files, with every hundredth entry a subdirectory or a symlink so
+/// that the listing isn't all one file type.
+fn populate(dir: &Path, entries: usize) {
+    fs::create_dir_all(dir).expect("create bench directory");
+    for i in 0..entries {
+        let path = dir.join(format!("entry-{:06}.dat", i));
+        match i % 100 {
+            0 => fs::create_dir(&path).expect("create subdirectory"),
+            1 => symlink("entry-000000.dat", &path).expect("create symlink"),
+            _ => fs::write(&path, i.to_string()).expect("create file"),
+        }
+    }
+}
+
+/// Runs `lslong` with `args` on `dir` `RUNS` times, returning the fastest and the mean.
+fn time_listing(dir: &Path, args: &[&str]) -> (Duration, Duration) {
+    let mut times = Vec::with_capacity(RUNS);
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let status = Command::new(env!("CARGO_BIN_EXE_lslong"))
+            .args(args)
+            .arg(dir)
+            .stdout(Stdio::null())
+            .status()
+            .expect("run lslong");
+        times.push(start.elapsed());
+        assert!(status.success(), "lslong {:?} failed: {}", args, status);
+    }
+    let fastest = times.iter().min().copied().unwrap_or_default();
+    (fastest, times.iter().sum::<Duration>() / RUNS as u32)
+}
+
+fn main() {
+    let entries = std::env::var("LSLONG_BENCH_ENTRIES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_ENTRIES);
+    let dir: PathBuf = std::env::temp_dir().join(format!("lslong-bench-{}", std::process::id()));
+    populate(&dir, entries);
+
+    let cases: [(&str, &[&str]); 5] = [
+        ("sequential", &[]),
+        ("parallel=2", &["--parallel=2"]),
+        ("parallel=8", &["--parallel=8"]),
+        ("parallel (default)", &["--parallel"]),
+        ("parallel, json", &["--parallel", "--format=json"]),
+    ];
+    println!("{} entries, {} runs each", entries, RUNS);
+    for (name, args) in cases {
+        let (fastest, mean) = time_listing(&dir, args);
+        println!("{:<20} min {:>9.1?}  mean {:>9.1?}", name, fastest, mean);
+    }
+
+    fs::remove_dir_all(&dir).expect("remove bench directory");
+}
@@ -0,0 +1,5 @@
+//! Library code shared by the translated programs, so other tools can use their logic without
+//! going through a binary.
+
+pub mod lslong;
+pub mod sync;
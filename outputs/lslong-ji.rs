@@ -1,125 +1,336 @@
-use std::collections::HashMap;
-use std::ffi::CString;
-use std::fs::{self, DirEntry};
+use std::fs;
+use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::Path;
-use std::ptr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicI32, Ordering as AtomicOrdering};
+use std::thread;
 
-fn expect<T>(ptr: Option<T>, msg: &str) {
-    if ptr.is_none() {
-        eprintln!("{}", msg);
-        std::process::exit(1);
-    }
+use chrono::{Local, SecondsFormat};
+use serde::Serialize;
+
+use translations::lslong::{
+    Change, DiskUsage, Entry, IdResolver, Inotify, ListError, LongRenderer, Options, OutputFormat,
+    POLL_INTERVAL, Record, RecordRenderer, Renderer, TreeSnapshot, USAGE, compare_snapshots, diff_snapshots,
+    is_selected, list_dir_reporting, parse_args, print_differences, snapshot, sort_entries, stat_entry,
+    walk_tree,
+};
+
+/// The exit status: 0, 1 for problems with entries found along the way, 2 for operands that
+/// can't be listed at all, as in GNU ls.
+static EXIT_STATUS: AtomicI32 = AtomicI32::new(0);
+
+fn set_exit_status(status: i32) {
+    EXIT_STATUS.fetch_max(status, AtomicOrdering::Relaxed);
 }
 
-fn uid_str(uid: u32) -> String {
-    match users::get_user_by_uid(uid) {
-        Some(user) => user.name().to_string(),
-        None => "Unknown".to_string(),
+/// Lists `dir`, then (with `-R`) each of its subdirectories under a `path:` header.
+/// `ancestors` holds the (dev, inode) pairs of the directories being listed above this one,
+/// so that symlink and bind-mount loops are reported instead of followed forever.
+/// `usage` sums up what `-R` goes through.
+fn display_dir(
+    dir: &Path,
+    opts: &Options,
+    renderer: &mut dyn Renderer,
+    ancestors: &mut Vec<(u64, u64)>,
+    usage: &mut DiskUsage,
+) {
+    let report = |e: ListError| {
+        eprintln!("lslong: {}", e);
+        set_exit_status(1);
+    };
+    let entries = match list_dir_reporting(dir, opts, report) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("lslong: {}", e);
+            // Only the operand itself is in `ancestors` at the top of the walk; failing to open
+            // an operand is serious, failing on a subdirectory is not.
+            let operand = ancestors.len() <= 1 && matches!(e, ListError::Open { .. });
+            set_exit_status(if operand { 2 } else { 1 });
+            return;
+        }
+    };
+
+    // Filters only decide what's printed; `-R` still descends into directories that fail them.
+    let shown: Vec<&Entry> = entries.iter().filter(|entry| is_selected(entry, opts)).collect();
+    renderer.directory(dir, &shown);
+    shown.iter().for_each(|entry| usage.add(entry));
+    if !opts.recursive {
+        return;
     }
-}
 
-fn gid_str(gid: u32) -> String {
-    match users::get_group_by_gid(gid) {
-        Some(group) => group.name().to_string(),
-        None => "Unknown".to_string(),
+    for entry in &entries {
+        if !entry.is_dir() || entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let subdir = &entry.path;
+        renderer.heading(subdir);
+        let id = match fs::metadata(subdir) {
+            Ok(info) => (info.dev(), info.ino()),
+            Err(source) => {
+                eprintln!("lslong: {}", ListError::Open { path: subdir.clone(), source });
+                set_exit_status(1);
+                continue;
+            }
+        };
+        if ancestors.contains(&id) {
+            eprintln!("{}: not listing already-listed directory", subdir.display());
+            set_exit_status(2);
+            continue;
+        }
+        ancestors.push(id);
+        display_dir(subdir, opts, renderer, ancestors, usage);
+        ancestors.pop();
     }
 }
 
-fn permbits_to_chars(permission_value: u32) -> String {
-    let mut chars = String::from("----------");
-    if permission_value & 0b100 != 0 {
-        chars.replace_range(0..1, "r");
-    }
-    if permission_value & 0b010 != 0 {
-        chars.replace_range(1..2, "w");
+/// Lists one directory operand, seeding the loop check with the operand itself. With `-R`,
+/// the listing ends with the apparent size and disk usage of everything in it.
+fn display_operand(dir: &Path, opts: &Options, renderer: &mut dyn Renderer) {
+    let mut ancestors = Vec::new();
+    if let Ok(info) = fs::metadata(dir) {
+        ancestors.push((info.dev(), info.ino()));
     }
-    if permission_value & 0b001 != 0 {
-        chars.replace_range(2..3, "x");
+    let mut usage = DiskUsage::default();
+    display_dir(dir, opts, renderer, &mut ancestors, &mut usage);
+    if opts.recursive {
+        renderer.usage(&usage);
     }
-    chars
 }
 
-fn get_file_mode(mode: u32) -> String {
-    let mut bits = String::from("----------");
-
-    bits.replace_range(0..1, match mode & libc::S_IFMT {
-        libc::S_IFREG => "-",
-        libc::S_IFDIR => "d",
-        libc::S_IFCHR => "c",
-        libc::S_IFBLK => "b",
-        _ => "?",
-    });
-
-    bits.replace_range(1..4, &permbits_to_chars(mode >> 6));
-    bits.replace_range(4..7, &permbits_to_chars(mode >> 3));
-    bits.replace_range(7..10, &permbits_to_chars(mode));
-
-    bits
+/// Prints a directory operand and everything beneath it as a tree.
+fn display_tree(root: Entry, opts: &Options, renderer: &mut dyn Renderer) {
+    let root_path = root.path.clone();
+    let (nodes, errors) = walk_tree(root, opts);
+    for e in &errors {
+        eprintln!("lslong: {}", e);
+        set_exit_status(if e.path() == root_path { 2 } else { 1 });
+    }
+    if nodes.iter().any(|node| node.note.is_some()) {
+        set_exit_status(2);
+    }
+    renderer.tree(&nodes);
 }
 
-fn display_file_info(fname: &str, info: &fs::Metadata) {
-    println!(
-        "{} {:>4} {:<8} {:<8} {:>8} {:.12} {}",
-        get_file_mode(info.mode()),
-        info.nlink(),
-        uid_str(info.uid()),
-        gid_str(info.gid()),
-        info.len(),
-        time_to_string(info.modified().unwrap_or(SystemTime::now())),
-        fname
-    );
+/// A `--watch` change as written with `--format=ndjson`.
+#[derive(Debug, Serialize)]
+struct WatchEvent {
+    event: &'static str,
+    time: String,
+    path: Option<String>,
+    from: Option<String>,
+    /// The entry's current status, when it still exists.
+    record: Option<Record>,
 }
 
-fn time_to_string(time: SystemTime) -> String {
-    let duration = time.duration_since(UNIX_EPOCH).expect("Time went backwards");
-    let datetime = chrono::NaiveDateTime::from_timestamp(duration.as_secs() as i64, 0);
-    datetime.format("%b %d %H:%M").to_string()
+/// How `--watch` reports changes: by redrawing the long listing, or as NDJSON events.
+enum WatchOutput<'a> {
+    Redraw(LongRenderer<'a>),
+    Events(IdResolver),
 }
 
-#[derive(Debug)]
-struct StatFileResult {
-    info: fs::Metadata,
-    ok: bool,
-}
+/// Reports a batch of changes: redraws the listing for the long format, or writes one NDJSON
+/// event per change.
+fn report_changes(dir: &Path, changes: &[Change], opts: &Options, output: &mut WatchOutput) {
+    if changes.is_empty() {
+        return;
+    }
+    let ids = match output {
+        WatchOutput::Redraw(renderer) => {
+            if unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 } {
+                renderer.clear_screen();
+            }
+            renderer.heading(dir);
+            display_operand(dir, opts, renderer);
+            return;
+        }
+        WatchOutput::Events(ids) => ids,
+    };
 
-fn stat_file(dirname: &str, fname: &str) -> StatFileResult {
-    let full_path = Path::new(dirname).join(fname);
-    match full_path.metadata() {
-        Ok(info) => StatFileResult { info, ok: true },
-        Err(_) => StatFileResult { info: fs::Metadata::default(), ok: false },
+    let time = Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false);
+    let path = |name: &String| dir.join(name).to_string_lossy().into_owned();
+    for change in changes {
+        let record = change.name.as_ref().and_then(|name| {
+            let entry = stat_entry(dir, name, opts).ok()?;
+            Some(Record::new(&entry, ids))
+        });
+        let event = WatchEvent {
+            event: change.event,
+            time: time.clone(),
+            path: change.name.as_ref().map(path),
+            from: change.from.as_ref().map(path),
+            record,
+        };
+        println!("{}", serde_json::to_string(&event).unwrap_or_default());
     }
 }
 
-fn display_dir(dirname: &str) {
-    match fs::read_dir(dirname) {
-        Ok(entries) => {
-            for entry in entries.filter_map(Result::ok) {
-                let stat_res = stat_file(dirname, entry.file_name().to_str().unwrap());
-                if stat_res.ok {
-                    display_file_info(entry.file_name().to_str().unwrap(), &stat_res.info);
+/// `--watch`: lists `dir`, then reports changes to it until it goes away, using inotify
+/// unless `--poll` is given or inotify isn't available. Returns the exit status.
+fn watch(dir: &Path, opts: &Options) -> i32 {
+    let mut output = if opts.format == OutputFormat::Long {
+        let mut renderer = LongRenderer::new(opts);
+        renderer.heading(dir);
+        display_operand(dir, opts, &mut renderer);
+        WatchOutput::Redraw(renderer)
+    } else {
+        WatchOutput::Events(IdResolver::new(opts))
+    };
+    let removed = || {
+        if opts.format == OutputFormat::Ndjson {
+            let time = Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false);
+            let path = Some(dir.to_string_lossy().into_owned());
+            let event = WatchEvent { event: "deleted", time, path, from: None, record: None };
+            println!("{}", serde_json::to_string(&event).unwrap_or_default());
+        }
+        eprintln!("lslong: '{}' is no longer there", dir.display());
+        1
+    };
+
+    let inotify = if opts.poll { None } else { Some(Inotify::new(dir)) };
+    match inotify {
+        Some(Ok(mut inotify)) => loop {
+            match inotify.next_changes() {
+                Ok(Some(changes)) => report_changes(dir, &changes, opts, &mut output),
+                Ok(None) => return removed(),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("lslong: reading inotify events: {}", e);
+                    return 2;
                 }
             }
         },
+        Some(Err(e)) => eprintln!("lslong: inotify unavailable ({}), polling instead", e),
+        None => {}
+    }
+
+    let mut before = match snapshot(dir, opts) {
+        Ok(entries) => entries,
         Err(e) => {
-            eprintln!("Cannot open directory '{}': {}", dirname, e);
+            eprintln!("lslong: {}", e);
+            return 2;
         }
+    };
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let Ok(after) = snapshot(dir, opts) else {
+            return removed();
+        };
+        report_changes(dir, &diff_snapshots(&before, &after), opts, &mut output);
+        before = after;
     }
 }
 
+/// `--snapshot FILE`: saves the state of the tree under `dir` to `out`. Returns the exit status.
+fn save_snapshot(dir: &Path, out: &Path, opts: &Options) -> i32 {
+    let (snapshot, errors) = TreeSnapshot::take(dir, opts);
+    for e in &errors {
+        eprintln!("lslong: {}", e);
+        set_exit_status(if e.path() == dir { 2 } else { 1 });
+    }
+    if let Err(e) = snapshot.save(out) {
+        eprintln!("lslong: {}", e);
+        set_exit_status(2);
+    }
+    EXIT_STATUS.load(AtomicOrdering::Relaxed)
+}
+
+/// `--diff BEFORE AFTER`: prints what changed between two snapshots. Returns 0 when nothing
+/// did and 1 when something did, as diff(1) does, or 2 when a snapshot can't be read.
+fn diff(before: &Path, after: &Path, opts: &Options) -> i32 {
+    let load = |path| TreeSnapshot::load(path).inspect_err(|e| eprintln!("lslong: {}", e));
+    let (Ok(before), Ok(after)) = (load(before), load(after)) else {
+        return 2;
+    };
+    if before.hidden != after.hidden {
+        eprintln!("lslong: warning: only one snapshot records dotfiles; they will show as added or removed");
+    }
+    let differences = compare_snapshots(&before, &after);
+    print_differences(&differences, opts.format);
+    if differences.is_empty() { 0 } else { 1 }
+}
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    unsafe { libc::setlocale(libc::LC_ALL, c"".as_ptr()) };
 
-    if args.len() == 1 {
-        display_dir(".");
-    } else {
-        for arg in args.iter().skip(1) {
-            println!("{}:", arg);
-            display_dir(arg);
-            if arg != args.last().unwrap() {
-                println!();
+    let (opts, operands) = match parse_args(std::env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("lslong: {}", e);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if opts.watch {
+        let dir = match operands.as_slice() {
+            [] => ".",
+            [dir] => dir.as_str(),
+            _ => {
+                eprintln!("lslong: --watch takes a single directory");
+                std::process::exit(2);
+            }
+        };
+        std::process::exit(watch(Path::new(dir), &opts));
+    }
+
+    if opts.diff {
+        let [before, after] = operands.as_slice() else {
+            eprintln!("lslong: --diff takes two snapshot files");
+            std::process::exit(2);
+        };
+        std::process::exit(diff(Path::new(before), Path::new(after), &opts));
+    }
+    if let Some(out) = &opts.snapshot {
+        let dir = match operands.as_slice() {
+            [] => ".",
+            [dir] => dir.as_str(),
+            _ => {
+                eprintln!("lslong: --snapshot takes a single directory");
+                std::process::exit(2);
+            }
+        };
+        std::process::exit(save_snapshot(Path::new(dir), out, &opts));
+    }
+
+    let mut renderer: Box<dyn Renderer> = match opts.format {
+        OutputFormat::Long => Box::new(LongRenderer::new(&opts)),
+        _ => Box::new(RecordRenderer::new(&opts)),
+    };
+
+    // With no operands, `.` is listed; its header only appears with `-R`. Trees start with a
+    // line for the directory itself instead.
+    let headers = !opts.tree && (opts.recursive || !operands.is_empty());
+    let operands = if operands.is_empty() { vec![".".to_string()] } else { operands };
+
+    // Files (and, with `-d`, directories) are listed together first, then each directory.
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    for operand in operands {
+        match stat_entry(Path::new(""), &operand, &opts) {
+            Ok(entry) if entry.is_dir() && !opts.directory => dirs.push(entry),
+            Ok(entry) => files.push(entry),
+            Err(e) => {
+                eprintln!("lslong: cannot access '{}': {}", operand, e);
+                set_exit_status(2);
             }
         }
     }
-}
\ No newline at end of file
+    sort_entries(&mut files, &opts);
+    sort_entries(&mut dirs, &opts);
+
+    let files: Vec<&Entry> = files.iter().filter(|file| is_selected(file, &opts)).collect();
+    renderer.files(&files);
+    for dir in dirs {
+        if opts.tree {
+            display_tree(dir, &opts, renderer.as_mut());
+            continue;
+        }
+        if headers {
+            renderer.heading(&dir.path);
+        }
+        display_operand(&dir.path, &opts, renderer.as_mut());
+    }
+    renderer.finish();
+
+    std::process::exit(EXIT_STATUS.load(AtomicOrdering::Relaxed));
+}
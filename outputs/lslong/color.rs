@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use super::entry::{Entry, FileType};
+
+/// When `--color` colors names.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    #[default]
+    Never,
+    Auto,
+    Always,
+}
+
+impl ColorMode {
+    pub fn parse(when: &str) -> Result<ColorMode, String> {
+        Ok(match when {
+            "always" | "yes" | "force" => ColorMode::Always,
+            "never" | "no" | "none" => ColorMode::Never,
+            "auto" | "tty" | "if-tty" => ColorMode::Auto,
+            _ => return Err(format!("invalid argument '{}' for '--color'", when)),
+        })
+    }
+
+    /// Whether names get colored, checking stdout for `--color=auto`.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorMode::Never => false,
+            ColorMode::Always => true,
+            ColorMode::Auto => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+        }
+    }
+}
+
+/// dircolors' built-in database, used when `LS_COLORS` is unset or empty.
+const DEFAULT_LS_COLORS: &str = "rs=0:di=01;34:ln=01;36:mh=00:pi=40;33:so=01;35:do=01;35:bd=40;33;01:\
+                                 cd=40;33;01:or=40;31;01:mi=00:su=37;41:sg=30;43:tw=30;42:ow=34;42:\
+                                 st=37;44:ex=01;32";
+
+/// A parsed `LS_COLORS`: SGR sequences keyed by file type (`di`, `ln`, ...) and by name
+/// suffix (`*.tar`), later suffix entries overriding earlier ones as in dircolors.
+#[derive(Debug, Default)]
+pub(crate) struct Colors {
+    types: HashMap<String, String>,
+    suffixes: Vec<(String, String)>,
+}
+
+impl Colors {
+    fn parse(spec: &str) -> Colors {
+        let mut colors = Colors::default();
+        for item in spec.split(':') {
+            let Some((key, sequence)) = item.split_once('=') else {
+                continue;
+            };
+            match key.strip_prefix('*') {
+                Some(suffix) => colors.suffixes.push((suffix.to_string(), sequence.to_string())),
+                None => {
+                    colors.types.insert(key.to_string(), sequence.to_string());
+                }
+            }
+        }
+        colors
+    }
+
+    pub(crate) fn from_env() -> Colors {
+        match std::env::var("LS_COLORS") {
+            Ok(spec) if !spec.is_empty() => Colors::parse(&spec),
+            _ => Colors::parse(DEFAULT_LS_COLORS),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.types.get(key).map(String::as_str).filter(|sequence| !sequence.is_empty())
+    }
+
+    /// Matches `*` patterns exactly first, then ignoring case, as GNU ls does.
+    fn suffix(&self, name: &str) -> Option<&str> {
+        let lower = name.to_lowercase();
+        let exact = self.suffixes.iter().rev().find(|(suffix, _)| name.ends_with(suffix.as_str()));
+        exact
+            .or_else(|| self.suffixes.iter().rev().find(|(suffix, _)| lower.ends_with(&suffix.to_lowercase())))
+            .map(|(_, sequence)| sequence.as_str())
+    }
+
+    /// The sequence for `name`, described by `entry`; `target` is what a symlink points to, or
+    /// `None` when the link is dangling.
+    pub(crate) fn sequence(&self, name: &str, entry: &Entry, target: Option<&Entry>) -> Option<&str> {
+        let set = |bit: u32| entry.permissions.mode() & bit != 0;
+        let keys = match entry.file_type {
+            FileType::Symlink => {
+                return match target {
+                    Some(target) if self.get("ln") == Some("target") => self.sequence(name, target, None),
+                    Some(_) => self.get("ln"),
+                    None => self.get("or").or_else(|| self.get("ln").filter(|&ln| ln != "target")),
+                };
+            }
+            FileType::Directory => {
+                let other_writable = set(libc::S_IWOTH);
+                let sticky = set(libc::S_ISVTX);
+                vec![(sticky && other_writable, "tw"), (other_writable, "ow"), (sticky, "st"), (true, "di")]
+            }
+            FileType::File => vec![
+                (set(libc::S_ISUID), "su"),
+                (set(libc::S_ISGID), "sg"),
+                (set(0o111), "ex"),
+                (entry.nlink > 1, "mh"),
+            ],
+            FileType::Fifo => vec![(true, "pi")],
+            FileType::Socket => vec![(true, "so")],
+            FileType::BlockDevice => vec![(true, "bd")],
+            FileType::CharDevice => vec![(true, "cd")],
+            FileType::Door => vec![(true, "do")],
+            FileType::Whiteout | FileType::Unknown => vec![],
+        };
+        keys.into_iter()
+            .filter(|&(applies, _)| applies)
+            .find_map(|(_, key)| self.get(key))
+            .or_else(|| if entry.is_file() { self.suffix(name).or_else(|| self.get("fi")) } else { None })
+    }
+
+    /// Wraps `text` in `sequence`, ending with the `rs` reset sequence.
+    pub(crate) fn paint(&self, sequence: Option<&str>, text: &str) -> String {
+        match sequence {
+            Some(sequence) => format!("\x1b[{}m{}\x1b[{}m", sequence, text, self.get("rs").unwrap_or("0")),
+            None => text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lslong::entry::Permissions;
+
+    fn entry(file_type: FileType, mode: u32) -> Entry {
+        let info = std::fs::symlink_metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        Entry {
+            file_type,
+            permissions: Permissions::from_mode(mode),
+            nlink: 1,
+            ..Entry::from_metadata("name", "name".into(), &info)
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        let colors = Colors::parse("di=01;34:no-equals:*.tar=01;31::*.TXT=32:ln=target:fi=:*.tar=35");
+        assert_eq!(colors.get("di"), Some("01;34"));
+        assert_eq!(colors.get("ln"), Some("target"));
+        // Empty sequences count as unset, and entries without `=` are skipped.
+        assert_eq!(colors.get("fi"), None);
+        assert_eq!(colors.get("no-equals"), None);
+        assert_eq!(colors.types.len(), 3);
+        // The later `*.tar` wins; case only matters when both cases are given.
+        assert_eq!(colors.suffix("a.tar"), Some("35"));
+        assert_eq!(colors.suffix("A.TAR"), Some("35"));
+        assert_eq!(colors.suffix("notes.txt"), Some("32"));
+        assert_eq!(colors.suffix("tar"), None);
+        let colors = Colors::parse("*.txt=31:*.TXT=32");
+        assert_eq!(colors.suffix("a.txt"), Some("31"));
+        assert_eq!(colors.suffix("a.TXT"), Some("32"));
+        assert_eq!(colors.suffix("a.Txt"), Some("32"));
+    }
+
+    #[test]
+    fn types() {
+        let colors = Colors::parse(DEFAULT_LS_COLORS);
+        let cases = [
+            (entry(FileType::Directory, 0o755), Some("01;34")),
+            (entry(FileType::Directory, 0o1777), Some("30;42")),
+            (entry(FileType::Directory, 0o777), Some("34;42")),
+            (entry(FileType::Directory, 0o1755), Some("37;44")),
+            (entry(FileType::File, 0o644), None),
+            (entry(FileType::File, 0o755), Some("01;32")),
+            (entry(FileType::File, 0o4755), Some("37;41")),
+            (entry(FileType::Fifo, 0o644), Some("40;33")),
+            (entry(FileType::Socket, 0o755), Some("01;35")),
+            (entry(FileType::BlockDevice, 0o660), Some("40;33;01")),
+            (entry(FileType::Unknown, 0), None),
+        ];
+        for (entry, expected) in &cases {
+            let mode = entry.mode_string();
+            assert_eq!(colors.sequence("name", entry, None), *expected, "{}", mode);
+        }
+        let colors = Colors::parse("fi=0:*.rs=33:ex=32");
+        assert_eq!(colors.sequence("main.rs", &entry(FileType::File, 0o644), None), Some("33"));
+        assert_eq!(colors.sequence("main.rs", &entry(FileType::File, 0o755), None), Some("32"));
+        assert_eq!(colors.sequence("main.c", &entry(FileType::File, 0o644), None), Some("0"));
+        assert_eq!(colors.sequence("dir.rs", &entry(FileType::Directory, 0o755), None), None);
+    }
+
+    #[test]
+    fn symlinks() {
+        let link = entry(FileType::Symlink, 0o777);
+        let dir = entry(FileType::Directory, 0o755);
+        let colors = Colors::parse("ln=01;36:or=40;31:di=01;34");
+        assert_eq!(colors.sequence("l", &link, Some(&dir)), Some("01;36"));
+        assert_eq!(colors.sequence("l", &link, None), Some("40;31"));
+        // `ln=target` colors links as what they point to; dangling ones fall back to `or`.
+        let colors = Colors::parse("ln=target:or=40;31:di=01;34");
+        assert_eq!(colors.sequence("l", &link, Some(&dir)), Some("01;34"));
+        assert_eq!(colors.sequence("l", &link, None), Some("40;31"));
+        let colors = Colors::parse("ln=target");
+        assert_eq!(colors.sequence("l", &link, None), None);
+        let colors = Colors::parse("ln=01;36");
+        assert_eq!(colors.sequence("l", &link, None), Some("01;36"));
+    }
+
+    #[test]
+    fn painting() {
+        let colors = Colors::parse("di=01;34");
+        assert_eq!(colors.paint(Some("01;34"), "src"), "\x1b[01;34msrc\x1b[0m");
+        assert_eq!(colors.paint(None, "src"), "src");
+        let colors = Colors::parse("rs=00;39");
+        assert_eq!(colors.paint(Some("32"), "x"), "\x1b[32mx\x1b[00;39m");
+    }
+}
+
@@ -0,0 +1,426 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use chrono::{DateTime, Local, TimeZone};
+
+use super::options::{Options, OutputFormat, TimeField};
+
+/// The kind of file an entry is, from the `S_IFMT` bits of its mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+    /// A Solaris door.
+    Door,
+    /// A BSD union-mount whiteout.
+    Whiteout,
+    Unknown,
+}
+
+/// File types Linux doesn't have, with the `S_IFMT` values of the systems that do.
+const S_IFDOOR: u32 = 0o150000;
+const S_IFWHT: u32 = 0o160000;
+
+impl FileType {
+    pub fn from_mode(mode: u32) -> FileType {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => FileType::File,
+            libc::S_IFDIR => FileType::Directory,
+            libc::S_IFLNK => FileType::Symlink,
+            libc::S_IFIFO => FileType::Fifo,
+            libc::S_IFSOCK => FileType::Socket,
+            libc::S_IFCHR => FileType::CharDevice,
+            libc::S_IFBLK => FileType::BlockDevice,
+            S_IFDOOR => FileType::Door,
+            S_IFWHT => FileType::Whiteout,
+            _ => FileType::Unknown,
+        }
+    }
+
+    /// The character `ls -l` shows before the permissions.
+    pub fn indicator(self) -> char {
+        match self {
+            FileType::File => '-',
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Door => 'D',
+            FileType::Whiteout => 'w',
+            FileType::Unknown => '?',
+        }
+    }
+
+    /// The name records use for the type.
+    pub fn name(self) -> &'static str {
+        match self {
+            FileType::File => "file",
+            FileType::Directory => "directory",
+            FileType::Symlink => "symlink",
+            FileType::Fifo => "fifo",
+            FileType::Socket => "socket",
+            FileType::CharDevice => "char_device",
+            FileType::BlockDevice => "block_device",
+            FileType::Door => "door",
+            FileType::Whiteout => "whiteout",
+            FileType::Unknown => "unknown",
+        }
+    }
+}
+
+/// The permission bits of a mode, setuid, setgid and sticky included. Displays as the nine
+/// `rwx` characters of `ls -l`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    mode: u32,
+}
+
+impl Permissions {
+    pub fn from_mode(mode: u32) -> Permissions {
+        Permissions { mode: mode & 0o7777 }
+    }
+
+    pub fn mode(self) -> u32 {
+        self.mode
+    }
+}
+
+fn permbits_to_chars(permission_value: u32) -> String {
+    let mut chars = String::from("---");
+    if permission_value & 0b100 != 0 {
+        chars.replace_range(0..1, "r");
+    }
+    if permission_value & 0b010 != 0 {
+        chars.replace_range(1..2, "w");
+    }
+    if permission_value & 0b001 != 0 {
+        chars.replace_range(2..3, "x");
+    }
+    chars
+}
+
+/// Overlays a setuid/setgid/sticky bit on an execute slot: `exec` when the slot is also
+/// executable, `noexec` when it isn't.
+fn special_bit(bits: &mut String, pos: usize, set: bool, exec: &str, noexec: &str) {
+    if set {
+        let replacement = if &bits[pos..pos + 1] == "x" { exec } else { noexec };
+        bits.replace_range(pos..pos + 1, replacement);
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = self.mode;
+        let mut bits = permbits_to_chars(mode >> 6) + &permbits_to_chars(mode >> 3) + &permbits_to_chars(mode);
+        special_bit(&mut bits, 2, mode & libc::S_ISUID != 0, "s", "S");
+        special_bit(&mut bits, 5, mode & libc::S_ISGID != 0, "s", "S");
+        special_bit(&mut bits, 8, mode & libc::S_ISVTX != 0, "t", "T");
+        write!(f, "{}", bits)
+    }
+}
+
+/// A stat'd directory entry. Listings only ask `statx` for the fields their format shows, so
+/// the others may be left at zero.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The name as listed: a file name inside a directory, or an operand as given.
+    pub name: String,
+    pub path: PathBuf,
+    pub file_type: FileType,
+    pub permissions: Permissions,
+    pub nlink: u64,
+    /// The owner's uid; an [`IdResolver`](super::IdResolver) turns it into a name.
+    pub uid: u32,
+    /// The group's gid.
+    pub gid: u32,
+    pub size: u64,
+    /// Allocated space in 512-byte blocks.
+    pub blocks: u64,
+    pub accessed: DateTime<Local>,
+    pub modified: DateTime<Local>,
+    pub changed: DateTime<Local>,
+    pub inode: u64,
+    pub device: u64,
+    /// Where a symlink points, when it could be read.
+    pub symlink_target: Option<PathBuf>,
+}
+
+impl Entry {
+    /// An entry for `path` from `symlink_metadata` (or `metadata`, to describe a link's target).
+    pub fn from_metadata(name: &str, path: PathBuf, info: &fs::Metadata) -> Entry {
+        Entry::new(name, path, &FileStat::from(info), None)
+    }
+
+    fn new(name: &str, path: PathBuf, stat: &FileStat, symlink_target: Option<PathBuf>) -> Entry {
+        Entry {
+            name: name.to_string(),
+            path,
+            file_type: FileType::from_mode(stat.mode),
+            permissions: Permissions::from_mode(stat.mode),
+            nlink: stat.nlink,
+            uid: stat.uid,
+            gid: stat.gid,
+            size: stat.size,
+            blocks: stat.blocks,
+            accessed: timestamp(stat.atime, stat.atime_nsec),
+            modified: timestamp(stat.mtime, stat.mtime_nsec),
+            changed: timestamp(stat.ctime, stat.ctime_nsec),
+            inode: stat.ino,
+            device: stat.dev,
+            symlink_target,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::File
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink
+    }
+
+    /// Whether this is a regular file with less than half its length allocated on disk, such
+    /// as a disk image with holes in it. Small files are never counted, since filesystems may
+    /// store their data inline with no blocks at all.
+    pub fn is_sparse(&self) -> bool {
+        let allocated = self.blocks * 512;
+        self.is_file() && self.size >= SPARSE_MIN_SIZE && allocated < self.size / 2
+    }
+
+    /// The full mode column of `ls -l`, such as `drwxr-xr-x`.
+    pub fn mode_string(&self) -> String {
+        format!("{}{}", self.file_type.indicator(), self.permissions)
+    }
+
+    /// The timestamp selected by `-u` (access), `-c` (status change) or, by default, modification.
+    pub fn time(&self, field: TimeField) -> DateTime<Local> {
+        match field {
+            TimeField::Modified => self.modified,
+            TimeField::Accessed => self.accessed,
+            TimeField::Changed => self.changed,
+        }
+    }
+}
+
+/// Files shorter than this are never reported as sparse.
+const SPARSE_MIN_SIZE: u64 = 64 * 1024;
+
+pub(crate) fn timestamp(secs: i64, nsecs: u32) -> DateTime<Local> {
+    Local.timestamp_opt(secs, nsecs).single().unwrap_or_default()
+}
+
+/// The parts of a file's status that lslong shows, as filled in by `statx` (or `lstat`).
+#[derive(Debug, Clone, Copy, Default)]
+struct FileStat {
+    mode: u32,
+    nlink: u64,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    blocks: u64,
+    atime: i64,
+    atime_nsec: u32,
+    mtime: i64,
+    mtime_nsec: u32,
+    ctime: i64,
+    ctime_nsec: u32,
+    ino: u64,
+    dev: u64,
+}
+
+impl From<&fs::Metadata> for FileStat {
+    fn from(info: &fs::Metadata) -> Self {
+        FileStat {
+            mode: info.mode(),
+            nlink: info.nlink(),
+            uid: info.uid(),
+            gid: info.gid(),
+            size: info.size(),
+            blocks: info.blocks(),
+            atime: info.atime(),
+            atime_nsec: info.atime_nsec() as u32,
+            mtime: info.mtime(),
+            mtime_nsec: info.mtime_nsec() as u32,
+            ctime: info.ctime(),
+            ctime_nsec: info.ctime_nsec() as u32,
+            ino: info.ino(),
+            dev: info.dev(),
+        }
+    }
+}
+
+impl From<&libc::statx> for FileStat {
+    fn from(stx: &libc::statx) -> Self {
+        FileStat {
+            mode: stx.stx_mode as u32,
+            nlink: stx.stx_nlink as u64,
+            uid: stx.stx_uid,
+            gid: stx.stx_gid,
+            size: stx.stx_size,
+            blocks: stx.stx_blocks,
+            atime: stx.stx_atime.tv_sec,
+            atime_nsec: stx.stx_atime.tv_nsec,
+            mtime: stx.stx_mtime.tv_sec,
+            mtime_nsec: stx.stx_mtime.tv_nsec,
+            ctime: stx.stx_ctime.tv_sec,
+            ctime_nsec: stx.stx_ctime.tv_nsec,
+            ino: stx.stx_ino,
+            dev: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+        }
+    }
+}
+
+/// Cleared the first time `statx` turns out to be missing (old kernels) or blocked (seccomp).
+static HAVE_STATX: AtomicBool = AtomicBool::new(true);
+
+/// The `statx` fields a listing needs: the long format shows one timestamp, records show all.
+/// `--mtime` and `--newer` need the modification time whichever one is shown.
+fn stat_mask(opts: &Options) -> u32 {
+    let mut time = match opts.time_field {
+        TimeField::Modified => libc::STATX_MTIME,
+        TimeField::Accessed => libc::STATX_ATIME,
+        TimeField::Changed => libc::STATX_CTIME,
+    };
+    if opts.filter.as_ref().is_some_and(|filter| filter.uses_mtime()) {
+        time |= libc::STATX_MTIME;
+    }
+    match opts.format {
+        // Snapshots and `--watch` compare every field `--diff` and `--poll` look at, whatever
+        // the format.
+        OutputFormat::Long if opts.snapshot.is_none() && !opts.watch => {
+            libc::STATX_TYPE
+                | libc::STATX_MODE
+                | libc::STATX_NLINK
+                | libc::STATX_UID
+                | libc::STATX_GID
+                | libc::STATX_SIZE
+                | libc::STATX_BLOCKS
+                | libc::STATX_INO
+                | time
+        }
+        _ => libc::STATX_BASIC_STATS,
+    }
+}
+
+/// Stats `path` without following symlinks, asking `statx` for just the fields in `mask`.
+fn lstat(path: &Path, mask: u32) -> io::Result<FileStat> {
+    if HAVE_STATX.load(AtomicOrdering::Relaxed) {
+        let cpath = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+        let flags = libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT;
+        if unsafe { libc::statx(libc::AT_FDCWD, cpath.as_ptr(), flags, mask, &mut stx) } == 0 {
+            return Ok(FileStat::from(&stx));
+        }
+        let err = io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) {
+            return Err(err);
+        }
+        HAVE_STATX.store(false, AtomicOrdering::Relaxed);
+    }
+    path.symlink_metadata().map(|info| FileStat::from(&info))
+}
+
+/// Stats `name` inside `dir` without following symlinks, so links are listed as links. An
+/// empty `dir` stats `name` as a path, as for command-line operands.
+pub fn stat_entry(dir: &Path, name: &str, opts: &Options) -> io::Result<Entry> {
+    let path = dir.join(name);
+    let stat = lstat(&path, stat_mask(opts))?;
+    let symlink_target = if FileType::from_mode(stat.mode) == FileType::Symlink {
+        fs::read_link(&path).ok()
+    } else {
+        None
+    };
+    Ok(Entry::new(name, path, &stat, symlink_target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lslong::parse_args;
+
+    fn mask(args: &[&str]) -> u32 {
+        let (opts, _) = parse_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        stat_mask(&opts)
+    }
+
+    #[test]
+    fn long_format_asks_for_the_shown_time() {
+        assert_ne!(mask(&[]) & libc::STATX_MTIME, 0);
+        assert_eq!(mask(&["-u"]) & libc::STATX_MTIME, 0);
+        assert_ne!(mask(&["-u"]) & libc::STATX_ATIME, 0);
+        assert_ne!(mask(&["-c"]) & libc::STATX_CTIME, 0);
+    }
+
+    #[test]
+    fn watching_asks_for_everything() {
+        assert_eq!(mask(&["--watch"]), libc::STATX_BASIC_STATS);
+        assert_eq!(mask(&["-u", "--watch", "--poll"]), libc::STATX_BASIC_STATS);
+        assert_eq!(mask(&["--snapshot", "out.json"]), libc::STATX_BASIC_STATS);
+    }
+
+    #[test]
+    fn file_types() {
+        let types = [
+            (libc::S_IFREG | 0o644, '-', "file"),
+            (libc::S_IFDIR | 0o755, 'd', "directory"),
+            (libc::S_IFLNK | 0o777, 'l', "symlink"),
+            (libc::S_IFIFO, 'p', "fifo"),
+            (libc::S_IFSOCK | 0o755, 's', "socket"),
+            (libc::S_IFCHR, 'c', "char_device"),
+            (libc::S_IFBLK, 'b', "block_device"),
+            (S_IFDOOR, 'D', "door"),
+            (S_IFWHT, 'w', "whiteout"),
+            (0, '?', "unknown"),
+            (0o170000, '?', "unknown"),
+        ];
+        for (mode, indicator, name) in types {
+            let file_type = FileType::from_mode(mode);
+            assert_eq!((file_type.indicator(), file_type.name()), (indicator, name), "{:o}", mode);
+        }
+    }
+
+    #[test]
+    fn permission_strings() {
+        let modes = [
+            (0o000, "---------"),
+            (0o644, "rw-r--r--"),
+            (0o755, "rwxr-xr-x"),
+            (0o4755, "rwsr-xr-x"),
+            (0o4644, "rwSr--r--"),
+            (0o2755, "rwxr-sr-x"),
+            (0o2745, "rwxr-Sr-x"),
+            (0o1777, "rwxrwxrwt"),
+            (0o1776, "rwxrwxrwT"),
+            (0o7000, "--S--S--T"),
+            (0o7777, "rwsrwsrwt"),
+        ];
+        for (mode, expected) in modes {
+            assert_eq!(Permissions::from_mode(libc::S_IFREG | mode).to_string(), expected, "{:o}", mode);
+        }
+        assert_eq!(Permissions::from_mode(libc::S_IFDIR | 0o1777).mode(), 0o1777);
+    }
+
+    #[test]
+    fn time_filters_ask_for_the_mtime() {
+        assert_ne!(mask(&["-u", "--mtime", "-1"]) & libc::STATX_MTIME, 0);
+        assert_ne!(mask(&["-c", "--not", "--newer", "/"]) & libc::STATX_MTIME, 0);
+        assert_eq!(mask(&["-u", "--name", "*.rs"]) & libc::STATX_MTIME, 0);
+    }
+}
@@ -0,0 +1,503 @@
+use std::ffi::CString;
+use std::fs;
+use std::iter::Peekable;
+use std::os::unix::fs::MetadataExt;
+
+use chrono::{DateTime, Local};
+
+use super::entry::{Entry, FileType, timestamp};
+
+/// How a find-style numeric argument compares: `+N` more than, `-N` less than, `N` exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Cmp {
+    Less,
+    Exactly,
+    More,
+}
+
+impl Cmp {
+    fn parse(arg: &str) -> (Cmp, &str) {
+        match arg.as_bytes().first() {
+            Some(b'+') => (Cmp::More, &arg[1..]),
+            Some(b'-') => (Cmp::Less, &arg[1..]),
+            _ => (Cmp::Exactly, arg),
+        }
+    }
+
+    fn test(self, value: i64, n: i64) -> bool {
+        match self {
+            Cmp::Less => value < n,
+            Cmp::Exactly => value == n,
+            Cmp::More => value > n,
+        }
+    }
+}
+
+/// How `--perm` compares: `MODE` exactly, `-MODE` all of the bits, `/MODE` any of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PermMatch {
+    Exact,
+    All,
+    Any,
+}
+
+/// A single find-style test on an entry.
+#[derive(Debug, Clone)]
+pub(crate) enum Test {
+    Name(CString),
+    Type(FileType),
+    /// Size rounded up to a whole number of `unit`-byte units, as in find.
+    Size(Cmp, i64, u64),
+    /// Modified after the reference file's mtime.
+    Newer(DateTime<Local>),
+    /// Age in whole days, measured from when lslong started.
+    Mtime(Cmp, i64, i64),
+    User(u32),
+    Perm(PermMatch, u32),
+}
+
+/// The options that take a test argument.
+pub(crate) const TEST_OPTIONS: [&str; 7] = ["name", "type", "size", "newer", "mtime", "user", "perm"];
+
+impl Test {
+    pub(crate) fn parse(option: &str, arg: &str) -> Result<Test, String> {
+        let invalid = || format!("invalid argument '{}' for '--{}'", arg, option);
+        Ok(match option {
+            "name" => Test::Name(CString::new(arg).map_err(|_| invalid())?),
+            "type" => Test::Type(match arg {
+                "f" => FileType::File,
+                "d" => FileType::Directory,
+                "l" => FileType::Symlink,
+                "p" => FileType::Fifo,
+                "s" => FileType::Socket,
+                "c" => FileType::CharDevice,
+                "b" => FileType::BlockDevice,
+                _ => return Err(invalid()),
+            }),
+            "size" => {
+                let (cmp, size) = Cmp::parse(arg);
+                let (digits, unit) = match size.char_indices().last() {
+                    Some((i, c)) if c.is_ascii_alphabetic() => (&size[..i], c),
+                    _ => (size, 'b'),
+                };
+                let unit = match unit {
+                    'c' => 1,
+                    'w' => 2,
+                    'b' => 512,
+                    'k' => 1 << 10,
+                    'M' => 1 << 20,
+                    'G' => 1 << 30,
+                    'T' => 1 << 40,
+                    _ => return Err(invalid()),
+                };
+                Test::Size(cmp, digits.parse().map_err(|_| invalid())?, unit)
+            }
+            "newer" => {
+                let info = fs::metadata(arg).map_err(|e| format!("cannot access '{}': {}", arg, e))?;
+                Test::Newer(timestamp(info.mtime(), info.mtime_nsec() as u32))
+            }
+            "mtime" => {
+                let (cmp, days) = Cmp::parse(arg);
+                Test::Mtime(cmp, days.parse().map_err(|_| invalid())?, Local::now().timestamp())
+            }
+            "user" => match users::get_user_by_name(arg) {
+                Some(user) => Test::User(user.uid()),
+                None => {
+                    let unknown = |_| format!("'{}' is not the name of a known user", arg);
+                    Test::User(arg.parse().map_err(unknown)?)
+                }
+            },
+            "perm" => {
+                let (matching, mode) = match arg.as_bytes().first() {
+                    Some(b'-') => (PermMatch::All, &arg[1..]),
+                    Some(b'/') => (PermMatch::Any, &arg[1..]),
+                    _ => (PermMatch::Exact, arg),
+                };
+                Test::Perm(matching, parse_mode(mode).ok_or_else(invalid)?)
+            }
+            _ => return Err(format!("unrecognized option '--{}'", option)),
+        })
+    }
+
+    fn matches(&self, name: &str, entry: &Entry) -> bool {
+        match self {
+            Test::Name(pattern) => match CString::new(name) {
+                Ok(name) => unsafe { libc::fnmatch(pattern.as_ptr(), name.as_ptr(), 0) == 0 },
+                Err(_) => false,
+            },
+            Test::Type(file_type) => entry.file_type == *file_type,
+            Test::Size(cmp, n, unit) => cmp.test(entry.size.div_ceil(*unit) as i64, *n),
+            Test::Newer(time) => entry.modified > *time,
+            Test::Mtime(cmp, days, now) => {
+                cmp.test((now - entry.modified.timestamp()).div_euclid(86_400), *days)
+            }
+            Test::User(uid) => entry.uid == *uid,
+            Test::Perm(matching, mode) => {
+                let bits = entry.permissions.mode();
+                match matching {
+                    PermMatch::Exact => bits == *mode,
+                    PermMatch::All => bits & mode == *mode,
+                    PermMatch::Any => *mode == 0 || bits & mode != 0,
+                }
+            }
+        }
+    }
+}
+
+/// Parses an octal mode, or a symbolic one such as `u+x,g=rw` applied to no permissions.
+fn parse_mode(spec: &str) -> Option<u32> {
+    if !spec.is_empty() && spec.bytes().all(|b| (b'0'..=b'7').contains(&b)) {
+        return u32::from_str_radix(spec, 8).ok().filter(|&mode| mode <= 0o7777);
+    }
+
+    let mut mode = 0;
+    for clause in spec.split(',') {
+        let op_at = clause.find(['+', '-', '='])?;
+        let (who, rest) = clause.split_at(op_at);
+        let who = if who.is_empty() { "a" } else { who };
+        let mut shifts = Vec::new();
+        for c in who.chars() {
+            match c {
+                'u' => shifts.push(6),
+                'g' => shifts.push(3),
+                'o' => shifts.push(0),
+                'a' => shifts.extend([6, 3, 0]),
+                _ => return None,
+            }
+        }
+
+        let mut bits = 0;
+        for c in rest[1..].chars() {
+            for &shift in &shifts {
+                bits |= match (c, shift) {
+                    ('r', _) => 0o4 << shift,
+                    ('w', _) => 0o2 << shift,
+                    ('x' | 'X', _) => 0o1 << shift,
+                    ('s', 6) => libc::S_ISUID,
+                    ('s', 3) => libc::S_ISGID,
+                    ('s', _) => 0,
+                    ('t', _) => libc::S_ISVTX,
+                    _ => return None,
+                };
+            }
+        }
+        match &rest[..1] {
+            "-" => mode &= !bits,
+            _ => mode |= bits,
+        }
+    }
+    Some(mode)
+}
+
+/// A tree of tests joined with `--and`, `--or` and `--not`, grouped with `(` and `)`.
+#[derive(Debug, Clone)]
+pub(crate) enum Filter {
+    Test(Test),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+/// A filter expression as it appears on the command line.
+#[derive(Debug)]
+pub(crate) enum FilterToken {
+    Test(Test),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl Filter {
+    /// Parses the tokens with find's precedence: `--not` binds tightest, then `--and` (which
+    /// may be left out between two tests), then `--or`.
+    pub(crate) fn parse(tokens: Vec<FilterToken>) -> Result<Option<Filter>, String> {
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        let mut tokens = tokens.into_iter().peekable();
+        let filter = Filter::parse_or(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(Some(filter)),
+            Some(_) => Err("unexpected ')' in filter expression".to_string()),
+        }
+    }
+
+    fn parse_or(tokens: &mut Peekable<impl Iterator<Item = FilterToken>>) -> Result<Filter, String> {
+        let mut filter = Filter::parse_and(tokens)?;
+        while matches!(tokens.peek(), Some(FilterToken::Or)) {
+            tokens.next();
+            filter = Filter::Or(Box::new(filter), Box::new(Filter::parse_and(tokens)?));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(tokens: &mut Peekable<impl Iterator<Item = FilterToken>>) -> Result<Filter, String> {
+        let mut filter = Filter::parse_not(tokens)?;
+        loop {
+            match tokens.peek() {
+                Some(FilterToken::And) => {
+                    tokens.next();
+                }
+                Some(FilterToken::Test(_) | FilterToken::Not | FilterToken::Open) => {}
+                _ => return Ok(filter),
+            }
+            filter = Filter::And(Box::new(filter), Box::new(Filter::parse_not(tokens)?));
+        }
+    }
+
+    fn parse_not(tokens: &mut Peekable<impl Iterator<Item = FilterToken>>) -> Result<Filter, String> {
+        match tokens.next() {
+            Some(FilterToken::Not) => Ok(Filter::Not(Box::new(Filter::parse_not(tokens)?))),
+            Some(FilterToken::Open) => {
+                let filter = Filter::parse_or(tokens)?;
+                match tokens.next() {
+                    Some(FilterToken::Close) => Ok(filter),
+                    _ => Err("missing ')' in filter expression".to_string()),
+                }
+            }
+            Some(FilterToken::Test(test)) => Ok(Filter::Test(test)),
+            Some(FilterToken::Close) => Err("unexpected ')' in filter expression".to_string()),
+            Some(FilterToken::And | FilterToken::Or) | None => {
+                Err("expected a test in filter expression".to_string())
+            }
+        }
+    }
+
+    /// Whether any test reads the modification time, which then has to be stat'd even when
+    /// `-u` or `-c` shows another time.
+    pub(crate) fn uses_mtime(&self) -> bool {
+        match self {
+            Filter::Test(test) => matches!(test, Test::Newer(_) | Test::Mtime(..)),
+            Filter::Not(filter) => filter.uses_mtime(),
+            Filter::And(a, b) | Filter::Or(a, b) => a.uses_mtime() || b.uses_mtime(),
+        }
+    }
+
+    pub(crate) fn matches(&self, name: &str, entry: &Entry) -> bool {
+        match self {
+            Filter::Test(test) => test.matches(name, entry),
+            Filter::Not(filter) => !filter.matches(name, entry),
+            Filter::And(a, b) => a.matches(name, entry) && b.matches(name, entry),
+            Filter::Or(a, b) => a.matches(name, entry) || b.matches(name, entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::lslong::Permissions;
+
+    fn entry(name: &str, mode: u32, size: u64, modified: i64) -> Entry {
+        let time = Local.timestamp_opt(modified, 0).unwrap();
+        Entry {
+            name: name.to_string(),
+            path: PathBuf::from(name),
+            file_type: FileType::from_mode(mode),
+            permissions: Permissions::from_mode(mode),
+            nlink: 1,
+            uid: 1000,
+            gid: 1000,
+            size,
+            blocks: size.div_ceil(512),
+            accessed: time,
+            modified: time,
+            changed: time,
+            inode: 1,
+            device: 1,
+            symlink_target: None,
+        }
+    }
+
+    fn test(option: &str, arg: &str) -> FilterToken {
+        FilterToken::Test(Test::parse(option, arg).unwrap())
+    }
+
+    /// The filter's shape, with `name` tests shown by their pattern.
+    fn shape(filter: &Filter) -> String {
+        match filter {
+            Filter::Test(Test::Name(pattern)) => pattern.to_string_lossy().into_owned(),
+            Filter::Test(test) => format!("{:?}", test),
+            Filter::Not(filter) => format!("!{}", shape(filter)),
+            Filter::And(a, b) => format!("({} & {})", shape(a), shape(b)),
+            Filter::Or(a, b) => format!("({} | {})", shape(a), shape(b)),
+        }
+    }
+
+    fn parse(tokens: Vec<FilterToken>) -> Result<String, String> {
+        Filter::parse(tokens).map(|filter| shape(&filter.unwrap()))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let tokens = vec![test("name", "a"), FilterToken::Or, test("name", "b"), test("name", "c")];
+        assert_eq!(parse(tokens), Ok("(a | (b & c))".to_string()));
+        let tokens =
+            vec![test("name", "a"), FilterToken::And, test("name", "b"), FilterToken::Or, test("name", "c")];
+        assert_eq!(parse(tokens), Ok("((a & b) | c)".to_string()));
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        let tokens = vec![FilterToken::Not, test("name", "a"), test("name", "b")];
+        assert_eq!(parse(tokens), Ok("(!a & b)".to_string()));
+        let tokens = vec![FilterToken::Not, FilterToken::Not, test("name", "a")];
+        assert_eq!(parse(tokens), Ok("!!a".to_string()));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let tokens = vec![
+            FilterToken::Not,
+            FilterToken::Open,
+            test("name", "a"),
+            FilterToken::Or,
+            test("name", "b"),
+            FilterToken::Close,
+            test("name", "c"),
+        ];
+        assert_eq!(parse(tokens), Ok("(!(a | b) & c)".to_string()));
+    }
+
+    #[test]
+    fn empty_expression_is_no_filter() {
+        assert!(Filter::parse(Vec::new()).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        let unexpected = Err("unexpected ')' in filter expression".to_string());
+        let missing = Err("missing ')' in filter expression".to_string());
+        let expected = Err("expected a test in filter expression".to_string());
+        assert_eq!(parse(vec![test("name", "a"), FilterToken::Close]), unexpected);
+        assert_eq!(parse(vec![FilterToken::Close]), unexpected);
+        assert_eq!(parse(vec![FilterToken::Open, test("name", "a")]), missing);
+        assert_eq!(parse(vec![FilterToken::Open, FilterToken::Close]), unexpected);
+        assert_eq!(parse(vec![test("name", "a"), FilterToken::And]), expected);
+        assert_eq!(parse(vec![FilterToken::Or, test("name", "a")]), expected);
+        assert_eq!(parse(vec![FilterToken::Not]), expected);
+    }
+
+    #[test]
+    fn octal_modes() {
+        assert_eq!(parse_mode("644"), Some(0o644));
+        assert_eq!(parse_mode("4755"), Some(0o4755));
+        assert_eq!(parse_mode("0"), Some(0));
+        assert_eq!(parse_mode("17777"), None);
+        assert_eq!(parse_mode("8"), None);
+    }
+
+    #[test]
+    fn symbolic_modes() {
+        assert_eq!(parse_mode("u+x"), Some(0o100));
+        assert_eq!(parse_mode("u=rw,go=r"), Some(0o644));
+        assert_eq!(parse_mode("+x"), Some(0o111));
+        assert_eq!(parse_mode("a+rwx,o-w"), Some(0o775));
+        assert_eq!(parse_mode("u+s"), Some(0o4000));
+        assert_eq!(parse_mode("g+s"), Some(0o2000));
+        assert_eq!(parse_mode("o+s"), Some(0));
+        assert_eq!(parse_mode("+t"), Some(0o1000));
+        assert_eq!(parse_mode(""), None);
+        assert_eq!(parse_mode("u"), None);
+        assert_eq!(parse_mode("z+x"), None);
+        assert_eq!(parse_mode("u+q"), None);
+    }
+
+    #[test]
+    fn invalid_test_arguments() {
+        assert_eq!(Test::parse("type", "x").unwrap_err(), "invalid argument 'x' for '--type'");
+        assert!(Test::parse("size", "10q").is_err());
+        assert!(Test::parse("size", "k").is_err());
+        assert!(Test::parse("mtime", "soon").is_err());
+        assert!(Test::parse("perm", "u+q").is_err());
+        assert!(Test::parse("newer", "/nonexistent/reference").is_err());
+        assert!(Test::parse("user", "no such user").is_err());
+        assert!(Test::parse("name", "a\0b").is_err());
+    }
+
+    #[test]
+    fn size_rounds_up_to_units() {
+        let file = entry("f", 0o100644, 1500, 0);
+        let size = |arg| Test::parse("size", arg).unwrap().matches("f", &file);
+        // 1500 bytes is 3 512-byte blocks and 2 KiB, rounded up.
+        assert!(size("3"));
+        assert!(size("+2"));
+        assert!(!size("-3"));
+        assert!(size("2k"));
+        assert!(size("1500c"));
+        assert!(size("750w"));
+        // As in find, any non-empty file is at least one unit, so it's never `-1M`.
+        assert!(size("1M"));
+        assert!(!size("-1M"));
+        assert!(size("+0"));
+    }
+
+    #[test]
+    fn perm_exact_all_any() {
+        let file = entry("f", 0o104755, 0, 0);
+        let perm = |arg| Test::parse("perm", arg).unwrap().matches("f", &file);
+        assert!(perm("4755"));
+        assert!(!perm("755"));
+        assert!(perm("-u+s"));
+        assert!(perm("-755"));
+        assert!(!perm("-u+s,o+w"));
+        assert!(perm("/u+s"));
+        assert!(perm("/o+w,u+s"));
+        assert!(!perm("/g+s,o+w"));
+        assert!(perm("/0"));
+    }
+
+    #[test]
+    fn mtime_counts_whole_days() {
+        let now = Local::now().timestamp();
+        let file = entry("f", 0o100644, 0, now - 36 * 3600);
+        let mtime = |cmp, days| Test::Mtime(cmp, days, now).matches("f", &file);
+        assert!(mtime(Cmp::Exactly, 1));
+        assert!(mtime(Cmp::More, 0));
+        assert!(mtime(Cmp::Less, 2));
+        assert!(!mtime(Cmp::Exactly, 0));
+        assert!(Test::parse("mtime", "-1").is_ok());
+    }
+
+    #[test]
+    fn newer_compares_mtimes() {
+        let reference = Local.timestamp_opt(1_000_000, 0).unwrap();
+        let newer = Test::Newer(reference);
+        assert!(newer.matches("f", &entry("f", 0o100644, 0, 1_000_001)));
+        assert!(!newer.matches("f", &entry("f", 0o100644, 0, 1_000_000)));
+    }
+
+    #[test]
+    fn name_type_and_combinations() {
+        let file = entry("notes.txt", 0o100644, 10, 0);
+        let dir = entry("src", 0o040755, 4096, 0);
+        let tokens = vec![
+            test("type", "d"),
+            FilterToken::Or,
+            FilterToken::Open,
+            test("name", "*.txt"),
+            FilterToken::Not,
+            test("size", "+1k"),
+            FilterToken::Close,
+        ];
+        let filter = Filter::parse(tokens).unwrap().unwrap();
+        assert!(filter.matches("notes.txt", &file));
+        assert!(filter.matches("src", &dir));
+        assert!(!filter.matches("notes.md", &entry("notes.md", 0o100644, 10, 0)));
+        assert!(!filter.matches("big.txt", &entry("big.txt", 0o100644, 4096, 0)));
+    }
+
+    #[test]
+    fn time_tests_need_the_mtime() {
+        let filter = |tokens| Filter::parse(tokens).unwrap().unwrap();
+        assert!(filter(vec![FilterToken::Not, test("mtime", "+1")]).uses_mtime());
+        assert!(filter(vec![test("name", "a"), FilterToken::Or, test("newer", "/")]).uses_mtime());
+        assert!(!filter(vec![test("name", "a"), test("size", "+1")]).uses_mtime());
+    }
+}
@@ -0,0 +1,1034 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::entry::Entry;
+
+/// A `.gitignore` (or `info/exclude`) pattern, matched against paths relative to the
+/// directory it came from.
+#[derive(Debug)]
+struct IgnorePattern {
+    base: String,
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    /// Patterns with a slash match the whole relative path; others match any basename.
+    anchored: bool,
+}
+
+impl IgnorePattern {
+    fn parse_file(contents: &str, base: &str) -> Vec<IgnorePattern> {
+        contents
+            .lines()
+            .filter_map(|line| {
+                let line = line.trim_end();
+                if line.is_empty() || line.starts_with('#') {
+                    return None;
+                }
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line.strip_prefix('\\').unwrap_or(line)),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let anchored = line.contains('/');
+                let pattern = line.strip_prefix('/').unwrap_or(line).to_string();
+                Some(IgnorePattern { base: base.to_string(), pattern, negated, dir_only, anchored })
+            })
+            .collect()
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(self.base.as_str()).and_then(|rest| rest.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+        let subject = if self.anchored { relative } else { relative.rsplit('/').next().unwrap_or(relative) };
+        wildmatch(self.pattern.as_bytes(), subject.as_bytes())
+    }
+}
+
+/// Git's wildmatch: `*` and `?` stop at `/`, `**` crosses directories, `[...]` is a class.
+fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    match first {
+        b'*' if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            match rest.split_first() {
+                None => true,
+                // `**/` matches zero or more whole directories.
+                Some((b'/', after)) => (0..=text.len())
+                    .filter(|&i| i == 0 || text[i - 1] == b'/')
+                    .any(|i| wildmatch(after, &text[i..])),
+                Some(_) => (0..=text.len()).any(|i| wildmatch(rest, &text[i..])),
+            }
+        }
+        b'*' => {
+            for i in 0..=text.len() {
+                if wildmatch(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    return false;
+                }
+            }
+            false
+        }
+        b'?' => matches!(text.split_first(), Some((&c, after)) if c != b'/' && wildmatch(rest, after)),
+        b'[' => {
+            let Some((&c, after)) = text.split_first() else {
+                return false;
+            };
+            let negated = matches!(rest.first(), Some(b'!' | b'^'));
+            let class = if negated { &rest[1..] } else { rest };
+            // A `]` straight after the `[` is part of the class.
+            let Some(end) = class.iter().skip(1).position(|&b| b == b']').map(|i| i + 1) else {
+                return c == b'[' && wildmatch(rest, after);
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < end {
+                if i + 2 < end && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            found != negated && c != b'/' && wildmatch(&class[end + 1..], after)
+        }
+        b'\\' if !rest.is_empty() => text.first() == rest.first() && wildmatch(&rest[1..], &text[1..]),
+        c => text.first() == Some(&c) && wildmatch(rest, &text[1..]),
+    }
+}
+
+fn be16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn be32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn be64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+type ObjectId = [u8; 20];
+
+/// A stage-0 entry of the git index, with the stat data git uses to skip rehashing.
+#[derive(Debug)]
+struct IndexEntry {
+    path: String,
+    id: ObjectId,
+    mode: u32,
+    mtime: (u32, u32),
+    size: u32,
+    /// Non-zero for the sides of an unresolved merge conflict.
+    stage: u16,
+}
+
+/// Why an index can't be used for `--git`.
+#[derive(Debug, PartialEq)]
+enum IndexError {
+    Malformed,
+    /// An index format that leaves entries out of this file, by name.
+    Unsupported(&'static str),
+}
+
+/// Parses `.git/index`, versions 2 to 4. Split indexes (whose entries are mostly in a shared
+/// index file) and sparse indexes (where whole directories stand in for their files) aren't
+/// read, since the entries here wouldn't be the full list.
+fn read_index(data: &[u8]) -> Result<Vec<IndexEntry>, IndexError> {
+    let (entries, mut pos) = read_index_entries(data).ok_or(IndexError::Malformed)?;
+    // Extensions follow the entries: a signature, a length and the data, up to the checksum.
+    while pos + 8 <= data.len().saturating_sub(20) {
+        match data.get(pos..pos + 4) {
+            Some(b"link") => return Err(IndexError::Unsupported("split index")),
+            Some(b"sdir") => return Err(IndexError::Unsupported("sparse index")),
+            _ => {}
+        }
+        pos += 8 + be32(data, pos + 4).ok_or(IndexError::Malformed)? as usize;
+    }
+    Ok(entries)
+}
+
+/// The entries of an index, and where the extensions after them start.
+fn read_index_entries(data: &[u8]) -> Option<(Vec<IndexEntry>, usize)> {
+    if data.get(..4)? != b"DIRC" {
+        return None;
+    }
+    let version = be32(data, 4)?;
+    let count = be32(data, 8)?;
+    let mut entries = Vec::with_capacity(count as usize);
+    let mut pos = 12;
+    let mut previous = Vec::new();
+    for _ in 0..count {
+        let start = pos;
+        let flags = be16(data, start + 60)?;
+        pos = start + 62;
+        if version >= 3 && flags & 0x4000 != 0 {
+            pos += 2;
+        }
+        let path = if version >= 4 {
+            // Version 4 strips the part of the previous path this one doesn't share.
+            let mut byte = *data.get(pos)?;
+            let mut strip = (byte & 0x7f) as usize;
+            while byte & 0x80 != 0 {
+                pos += 1;
+                byte = *data.get(pos)?;
+                strip = ((strip + 1) << 7) | (byte & 0x7f) as usize;
+            }
+            pos += 1;
+            let len = data.get(pos..)?.iter().position(|&b| b == 0)?;
+            previous.truncate(previous.len().checked_sub(strip)?);
+            previous.extend_from_slice(&data[pos..pos + len]);
+            pos += len + 1;
+            previous.clone()
+        } else {
+            let len = data.get(pos..)?.iter().position(|&b| b == 0)?;
+            let path = data[pos..pos + len].to_vec();
+            // Entries are NUL-padded to a multiple of eight bytes.
+            pos = start + (pos - start + len + 8) / 8 * 8;
+            path
+        };
+        entries.push(IndexEntry {
+            path: String::from_utf8_lossy(&path).into_owned(),
+            id: data.get(start + 40..start + 60)?.try_into().ok()?,
+            mode: be32(data, start + 24)?,
+            mtime: (be32(data, start + 8)?, be32(data, start + 12)?),
+            size: be32(data, start + 36)?,
+            stage: (flags >> 12) & 3,
+        });
+    }
+    Some((entries, pos))
+}
+
+/// A read-only memory map of a packfile or pack index.
+struct Mapped {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapped {
+    fn open(path: &Path) -> Option<Mapped> {
+        let file = fs::File::open(path).ok()?;
+        let len = file.metadata().ok()?.len() as usize;
+        if len == 0 {
+            return None;
+        }
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        (ptr != libc::MAP_FAILED).then_some(Mapped { ptr, len })
+    }
+}
+
+impl std::ops::Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.cast(), self.len) }
+    }
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// A packfile and its version 2 index.
+struct Pack {
+    index: Mapped,
+    data: Mapped,
+}
+
+impl Pack {
+    /// The offset of `id` in the packfile, by binary search of the index.
+    fn find(&self, id: &ObjectId) -> Option<u64> {
+        let index = &self.index;
+        if index.get(..8)? != [0xff, b't', b'O', b'c', 0, 0, 0, 2] {
+            return None;
+        }
+        let fanout = |byte: usize| be32(index, 8 + byte * 4).map(|n| n as usize);
+        let count = fanout(255)?;
+        let mut lo = if id[0] == 0 { 0 } else { fanout(id[0] as usize - 1)? };
+        let mut hi = fanout(id[0] as usize)?;
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let at = 1032 + mid * 20;
+            match index.get(at..at + 20)?.cmp(id) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => {
+                    let offset = be32(index, 1032 + count * 24 + mid * 4)?;
+                    if offset & 0x8000_0000 == 0 {
+                        return Some(offset as u64);
+                    }
+                    let large = (offset & 0x7fff_ffff) as usize;
+                    return be64(index, 1032 + count * 28 + large * 8);
+                }
+            }
+        }
+        None
+    }
+
+    /// Reads the object at `offset`, resolving deltas against their bases.
+    fn read_at(&self, offset: u64, store: &ObjectStore) -> Option<(u8, Vec<u8>)> {
+        let data = &self.data;
+        let mut pos = offset as usize;
+        let mut byte = *data.get(pos)?;
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 0x0f) as usize;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            pos += 1;
+            byte = *data.get(pos)?;
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+        pos += 1;
+
+        let (base_kind, base) = match kind {
+            1..=4 => return Some((kind, inflate(data.get(pos..)?, size)?)),
+            6 => {
+                let mut byte = *data.get(pos)?;
+                let mut back = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    pos += 1;
+                    byte = *data.get(pos)?;
+                    back = ((back + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                pos += 1;
+                self.read_at(offset.checked_sub(back)?, store)?
+            }
+            7 => {
+                let base: ObjectId = data.get(pos..pos + 20)?.try_into().ok()?;
+                pos += 20;
+                store.read(&base)?
+            }
+            _ => return None,
+        };
+        let delta = inflate(data.get(pos..)?, size)?;
+        Some((base_kind, apply_delta(&base, &delta)?))
+    }
+}
+
+/// Inflates the zlib stream at the start of `input`, which is `size` bytes uncompressed.
+fn inflate(input: &[u8], size: usize) -> Option<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(input, size + 1).ok()
+}
+
+/// Applies a git delta (copy and insert instructions) to `base`.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    let mut varint = || {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = *delta.get(pos)?;
+            pos += 1;
+            value |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    };
+    let _base_size = varint()?;
+    let result_size = varint()?;
+
+    let mut out = Vec::with_capacity(result_size);
+    while let Some(&op) = delta.get(pos) {
+        pos += 1;
+        if op & 0x80 != 0 {
+            let mut field = |bits: u8, count: usize| -> Option<usize> {
+                let mut value = 0;
+                for i in 0..count {
+                    if bits & (1 << i) != 0 {
+                        value |= (*delta.get(pos)? as usize) << (8 * i);
+                        pos += 1;
+                    }
+                }
+                Some(value)
+            };
+            let offset = field(op & 0x0f, 4)?;
+            let len = match field(op >> 4 & 0x07, 3)? {
+                0 => 0x10000,
+                len => len,
+            };
+            out.extend_from_slice(base.get(offset..offset + len)?);
+        } else if op != 0 {
+            out.extend_from_slice(delta.get(pos..pos + op as usize)?);
+            pos += op as usize;
+        } else {
+            return None;
+        }
+    }
+    (out.len() == result_size).then_some(out)
+}
+
+/// A blob's mode and id in a tree.
+type TreeEntry = (u32, ObjectId);
+
+/// A repository's object database: loose objects and packs.
+struct ObjectStore {
+    dir: PathBuf,
+    packs: Vec<Pack>,
+}
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+
+impl ObjectStore {
+    fn open(dir: PathBuf) -> ObjectStore {
+        let packs = fs::read_dir(dir.join("pack"))
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .filter_map(|index| {
+                let data = Mapped::open(&index.with_extension("pack"))?;
+                Some(Pack { index: Mapped::open(&index)?, data })
+            })
+            .collect();
+        ObjectStore { dir, packs }
+    }
+
+    fn read(&self, id: &ObjectId) -> Option<(u8, Vec<u8>)> {
+        let hex = to_hex(id);
+        if let Ok(compressed) = fs::read(self.dir.join(&hex[..2]).join(&hex[2..])) {
+            let raw = miniz_oxide::inflate::decompress_to_vec_zlib(&compressed).ok()?;
+            let header_len = raw.iter().position(|&b| b == 0)?;
+            let kind = match raw[..header_len].split(|&b| b == b' ').next()? {
+                b"commit" => OBJ_COMMIT,
+                b"tree" => OBJ_TREE,
+                b"blob" => 3,
+                _ => 4,
+            };
+            return Some((kind, raw[header_len + 1..].to_vec()));
+        }
+        self.packs.iter().find_map(|pack| pack.read_at(pack.find(id)?, self))
+    }
+
+    /// Every blob reachable from the tree `id`, as path -> (mode, id).
+    fn flatten_tree(&self, id: &ObjectId, prefix: &str, out: &mut HashMap<String, TreeEntry>) -> Option<()> {
+        let (kind, data) = self.read(id)?;
+        if kind != OBJ_TREE {
+            return None;
+        }
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let space = rest.iter().position(|&b| b == b' ')?;
+            let mode = u32::from_str_radix(std::str::from_utf8(&rest[..space]).ok()?, 8).ok()?;
+            let nul = space + rest[space..].iter().position(|&b| b == 0)?;
+            let name = String::from_utf8_lossy(&rest[space + 1..nul]);
+            let child: ObjectId = rest.get(nul + 1..nul + 21)?.try_into().ok()?;
+            rest = &rest[nul + 21..];
+            let path = if prefix.is_empty() { name.into_owned() } else { format!("{}/{}", prefix, name) };
+            if mode == 0o40000 {
+                self.flatten_tree(&child, &path, out)?;
+            } else {
+                out.insert(path, (mode, child));
+            }
+        }
+        Some(())
+    }
+}
+
+fn to_hex(id: &[u8]) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<ObjectId> {
+    let hex = hex.trim();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut id = [0; 20];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(id)
+}
+
+/// The commit HEAD points at, following a symbolic ref through loose refs and `packed-refs`.
+/// `None` on an unborn branch.
+fn resolve_head(git_dir: &Path, common_dir: &Path) -> Option<ObjectId> {
+    let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let Some(name) = head.trim().strip_prefix("ref: ") else {
+        return from_hex(&head);
+    };
+    for dir in [git_dir, common_dir] {
+        if let Ok(id) = fs::read_to_string(dir.join(name)) {
+            return from_hex(&id);
+        }
+    }
+    let packed = fs::read_to_string(common_dir.join("packed-refs")).ok()?;
+    packed.lines().find_map(|line| match line.split_once(' ') {
+        Some((id, refname)) if refname == name => from_hex(id),
+        _ => None,
+    })
+}
+
+/// SHA-1 of `contents` as a git blob.
+fn blob_id(contents: &[u8]) -> ObjectId {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", contents.len()).as_bytes());
+    hasher.update(contents);
+    hasher.digest().bytes()
+}
+
+/// The work-tree side of a tracked file's status: ` ` clean, `M` modified, `D` deleted or `T`
+/// changed type. Files whose size and mtime match the index are taken as clean without
+/// rehashing, as git does.
+fn worktree_status(workdir: &Path, entry: &IndexEntry) -> u8 {
+    const GITLINK: u32 = 0o160000;
+    if entry.mode == GITLINK {
+        return b' ';
+    }
+    let path = workdir.join(&entry.path);
+    let Ok(info) = fs::symlink_metadata(&path) else {
+        return b'D';
+    };
+    let is_link = entry.mode & libc::S_IFMT == libc::S_IFLNK;
+    if info.file_type().is_symlink() != is_link {
+        return b'T';
+    }
+    if !is_link && (info.mode() & 0o100 != 0) != (entry.mode & 0o100 != 0) {
+        return b'M';
+    }
+    if info.size() as u32 == entry.size && (info.mtime() as u32, info.mtime_nsec() as u32) == entry.mtime {
+        return b' ';
+    }
+    let contents = if is_link {
+        fs::read_link(&path).map(|target| target.as_os_str().as_bytes().to_vec())
+    } else {
+        fs::read(&path)
+    };
+    match contents {
+        Ok(contents) if blob_id(&contents) == entry.id => b' ',
+        _ => b'M',
+    }
+}
+
+/// The `--git` status of every path in one repository that isn't clean, keyed by its
+/// `/`-separated path relative to the work tree, as the two characters of `git status -s`.
+struct GitStatus {
+    workdir: PathBuf,
+    paths: HashMap<String, [u8; 2]>,
+    /// The combined status of everything inside each directory, `""` being the work tree.
+    dirs: HashMap<String, [u8; 2]>,
+    /// Directories holding tracked files, which are never reported as untracked as a whole.
+    tracked_dirs: HashSet<String>,
+}
+
+/// Whichever of two status characters matters more, for a directory summing up its contents.
+fn stronger(a: u8, b: u8) -> u8 {
+    const RANK: &[u8] = b"UMTDA?";
+    let rank = |c: u8| RANK.iter().position(|&r| r == c).unwrap_or(RANK.len());
+    if rank(b) < rank(a) { b } else { a }
+}
+
+/// Combines the statuses in `paths` into one for each directory above them: the most
+/// important staged and work-tree changes, with `?` for untracked files. Ignored paths don't
+/// count.
+fn dir_statuses(paths: &HashMap<String, [u8; 2]>) -> HashMap<String, [u8; 2]> {
+    let mut dirs = HashMap::new();
+    for (path, status) in paths.iter().filter(|&(_, status)| status != b"!!") {
+        let staged = if status == b"??" { b' ' } else { status[0] };
+        let mut dir = path.as_str();
+        while !dir.is_empty() {
+            dir = dir.rsplit_once('/').map_or("", |(parent, _)| parent);
+            let combined = dirs.entry(dir.to_string()).or_insert(*b"  ");
+            *combined = [stronger(combined[0], staged), stronger(combined[1], status[1])];
+        }
+    }
+    dirs
+}
+
+impl GitStatus {
+    /// Reads the repository's status, or warns and returns `None` if its index can't be read.
+    /// A repository without an index yet has nothing tracked.
+    fn load(workdir: PathBuf, git_dir: &Path) -> Option<GitStatus> {
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(dir) => git_dir.join(dir.trim()),
+            Err(_) => git_dir.to_path_buf(),
+        };
+        let index = match fs::read(git_dir.join("index")).map(|data| read_index(&data)) {
+            Ok(Ok(index)) => index,
+            Ok(Err(IndexError::Unsupported(format))) => {
+                eprintln!("lslong: {}: {} not supported, leaving --git blank", workdir.display(), format);
+                return None;
+            }
+            Ok(Err(IndexError::Malformed)) => {
+                eprintln!("lslong: {}: malformed git index, leaving --git blank", workdir.display());
+                return None;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                eprintln!("lslong: {}: cannot read git index: {}, leaving --git blank", workdir.display(), e);
+                return None;
+            }
+        };
+
+        let store = ObjectStore::open(common_dir.join("objects"));
+        let mut head = HashMap::new();
+        let head_tree = resolve_head(git_dir, &common_dir).and_then(|commit| match store.read(&commit)? {
+            (OBJ_COMMIT, data) => from_hex(std::str::from_utf8(data.get(5..45)?).ok()?),
+            _ => None,
+        });
+        if let Some(tree) = head_tree {
+            store.flatten_tree(&tree, "", &mut head);
+        }
+
+        let mut paths = HashMap::new();
+        for entry in &index {
+            let status = if entry.stage != 0 {
+                *b"UU"
+            } else {
+                let staged = match head.get(&entry.path) {
+                    None => b'A',
+                    Some(&(mode, id)) if (mode, id) != (entry.mode, entry.id) => b'M',
+                    Some(_) => b' ',
+                };
+                [staged, worktree_status(&workdir, entry)]
+            };
+            if status != *b"  " {
+                paths.insert(entry.path.clone(), status);
+            }
+        }
+        let tracked: HashSet<&str> = index.iter().map(|entry| entry.path.as_str()).collect();
+        for path in head.keys() {
+            if !tracked.contains(path.as_str()) {
+                paths.insert(path.clone(), *b"D ");
+            }
+        }
+
+        let mut tracked_dirs = HashSet::new();
+        for entry in &index {
+            let mut path = entry.path.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                if !tracked_dirs.insert(parent.to_string()) {
+                    break;
+                }
+                path = parent;
+            }
+        }
+        let mut ignores = fs::read_to_string(common_dir.join("info").join("exclude"))
+            .map(|contents| IgnorePattern::parse_file(&contents, ""))
+            .unwrap_or_default();
+        UntrackedScan { workdir: &workdir, tracked: &tracked, tracked_dirs: &tracked_dirs, paths: &mut paths }
+            .scan("", false, &mut ignores);
+
+        let dirs = dir_statuses(&paths);
+        Some(GitStatus { workdir, paths, dirs, tracked_dirs })
+    }
+
+    /// The status of the path `rel` (relative to the work tree). A directory without its own
+    /// status combines those of everything inside it: the most important staged and work-tree
+    /// changes, with `?` for untracked files, or `??` if nothing in it is tracked.
+    fn status(&self, rel: &str, is_dir: bool) -> [u8; 2] {
+        if let Some(&status) = self.paths.get(rel) {
+            return status;
+        }
+        // Everything inside an ignored or untracked directory shares its status.
+        let mut ancestor = rel;
+        while let Some((parent, _)) = ancestor.rsplit_once('/') {
+            if let Some(&status) = self.paths.get(parent).filter(|&status| status == b"!!" || status == b"??") {
+                return status;
+            }
+            ancestor = parent;
+        }
+        if !is_dir {
+            return *b"  ";
+        }
+        let combined = self.dirs.get(rel).copied().unwrap_or(*b"  ");
+        let untracked = !rel.is_empty() && !self.tracked_dirs.contains(rel);
+        if untracked && combined[1] == b'?' { *b"??" } else { combined }
+    }
+}
+
+/// The work-tree walk that finds untracked (`??`) and ignored (`!!`) paths.
+struct UntrackedScan<'a> {
+    workdir: &'a Path,
+    tracked: &'a HashSet<&'a str>,
+    tracked_dirs: &'a HashSet<String>,
+    paths: &'a mut HashMap<String, [u8; 2]>,
+}
+
+impl UntrackedScan<'_> {
+    /// Scans the directory `dir`; `ignored` is set inside ignored directories that still hold
+    /// tracked files. Ignored directories with nothing tracked are reported whole.
+    fn scan(&mut self, dir: &str, ignored: bool, ignores: &mut Vec<IgnorePattern>) {
+        let abs = self.workdir.join(dir);
+        let inherited = ignores.len();
+        if let Ok(contents) = fs::read_to_string(abs.join(".gitignore")) {
+            ignores.extend(IgnorePattern::parse_file(&contents, dir));
+        }
+        let is_ignored = |path: &str, is_dir: bool, ignores: &[IgnorePattern]| {
+            let last = ignores.iter().rev().find(|pattern| pattern.matches(path, is_dir));
+            last.is_some_and(|pattern| !pattern.negated)
+        };
+
+        for entry in fs::read_dir(&abs).into_iter().flatten().filter_map(Result::ok) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == ".git" {
+                continue;
+            }
+            let path = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if self.tracked.contains(path.as_str()) {
+                continue;
+            }
+            let ignored = ignored || is_ignored(&path, is_dir, ignores);
+            if is_dir && self.tracked_dirs.contains(&path) {
+                self.scan(&path, ignored, ignores);
+            } else if ignored {
+                self.paths.insert(path, *b"!!");
+            } else if is_dir && !entry.path().join(".git").exists() {
+                self.scan(&path, false, ignores);
+            } else {
+                self.paths.insert(path, *b"??");
+            }
+        }
+        ignores.truncate(inherited);
+    }
+}
+
+/// Finds the repository enclosing `dir` by walking up to a `.git` directory, or a `.git`
+/// file pointing elsewhere as in worktrees and submodules. Returns (work tree, git dir).
+fn discover_repo(dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    for ancestor in dir.ancestors() {
+        let dot_git = ancestor.join(".git");
+        if dot_git.is_dir() {
+            return Some((ancestor.to_path_buf(), dot_git));
+        }
+        if let Ok(contents) = fs::read_to_string(&dot_git) {
+            let git_dir = contents.trim().strip_prefix("gitdir: ")?;
+            return Some((ancestor.to_path_buf(), ancestor.join(git_dir)));
+        }
+    }
+    None
+}
+
+/// Repositories already scanned for `--git`, found by the directory an entry is in.
+#[derive(Default)]
+struct GitCache {
+    by_dir: HashMap<PathBuf, Option<Rc<GitStatus>>>,
+    by_workdir: HashMap<PathBuf, Option<Rc<GitStatus>>>,
+}
+
+thread_local! {
+    static GIT_CACHE: RefCell<GitCache> = RefCell::default();
+}
+
+/// The `--git` column for an entry: its two-character status with `-` for unchanged, or
+/// blank outside any repository.
+pub(crate) fn git_column(entry: &Entry) -> String {
+    let path = &entry.path;
+    // Resolve `.`, `..` and symlinked parents, but not the entry itself if it's a link.
+    let abs = if entry.is_symlink() {
+        let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        fs::canonicalize(parent).ok().zip(path.file_name()).map(|(parent, name)| parent.join(name))
+    } else {
+        fs::canonicalize(path).ok()
+    };
+    let Some((abs, parent)) = abs.as_ref().and_then(|abs| Some((abs, abs.parent()?))) else {
+        return "  ".to_string();
+    };
+
+    let repo_for = |dir: &Path| {
+        GIT_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if let Some(status) = cache.by_dir.get(dir) {
+                return status.clone();
+            }
+            let status = discover_repo(dir).and_then(|(workdir, git_dir)| {
+                cache
+                    .by_workdir
+                    .entry(workdir.clone())
+                    .or_insert_with(|| GitStatus::load(workdir, &git_dir).map(Rc::new))
+                    .clone()
+            });
+            cache.by_dir.insert(dir.to_path_buf(), status.clone());
+            status
+        })
+    };
+    // A repository's own top directory (as `.`, say) sums up the whole work tree.
+    let repo = repo_for(parent).or_else(|| entry.is_dir().then(|| repo_for(abs)).flatten());
+    let Some((repo, rel)) = repo.as_ref().and_then(|repo| Some((repo, abs.strip_prefix(&repo.workdir).ok()?)))
+    else {
+        return "  ".to_string();
+    };
+    let status = repo.status(&rel.to_string_lossy(), entry.is_dir());
+    status.iter().map(|&c| if c == b' ' { '-' } else { c as char }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildmatch(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn wildmatch_stars() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(matches("*", ""));
+        assert!(!matches("*.txt", "dir/a.txt"));
+        assert!(!matches("a*b", "a/b"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?", "/"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn wildmatch_double_stars() {
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("**/c", "c"));
+        assert!(matches("**/c", "a/b/c"));
+        assert!(!matches("**/c", "a/bc"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "ab"));
+        assert!(matches("a/**", "a/x/y"));
+        assert!(matches("a**z", "a/b/z"));
+    }
+
+    #[test]
+    fn wildmatch_classes() {
+        assert!(matches("[abc].rs", "b.rs"));
+        assert!(!matches("[abc].rs", "d.rs"));
+        assert!(matches("x[0-9]", "x7"));
+        assert!(!matches("x[0-9]", "xa"));
+        assert!(matches("[a-cx-z]", "y"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(!matches("[!a-c]", "b"));
+        assert!(matches("[^a]", "b"));
+        assert!(!matches("[^a]", "a"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(!matches("[!a]", "/"));
+        // An unclosed `[` is an ordinary character.
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn wildmatch_escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a\\?", "a?"));
+        assert!(!matches("a\\?", "ab"));
+    }
+
+    #[test]
+    fn ignore_patterns() {
+        let patterns = IgnorePattern::parse_file(
+            "# comment\n\n*.log\n!keep.log\nbuild/\n/top.txt\ndocs/*.html\n\\!bang\n",
+            "",
+        );
+        let shape: Vec<_> =
+            patterns.iter().map(|p| (p.pattern.as_str(), p.negated, p.dir_only, p.anchored)).collect();
+        assert_eq!(
+            shape,
+            [
+                ("*.log", false, false, false),
+                ("keep.log", true, false, false),
+                ("build", false, true, false),
+                ("top.txt", false, false, true),
+                ("docs/*.html", false, false, true),
+                ("!bang", false, false, false),
+            ]
+        );
+        let [log, keep, build, top, html, bang] = &patterns[..] else { unreachable!() };
+        assert!(log.matches("a/b/x.log", false));
+        assert!(keep.matches("sub/keep.log", false));
+        // A trailing `/` only matches directories, at any depth.
+        assert!(build.matches("build", true));
+        assert!(build.matches("src/build", true));
+        assert!(!build.matches("build", false));
+        // A slash anchors the pattern to its own directory.
+        assert!(top.matches("top.txt", false));
+        assert!(!top.matches("sub/top.txt", false));
+        assert!(html.matches("docs/index.html", false));
+        assert!(!html.matches("docs/api/index.html", false));
+        assert!(!html.matches("src/docs/index.html", false));
+        assert!(bang.matches("!bang", false));
+    }
+
+    #[test]
+    fn ignore_patterns_from_subdirectories() {
+        let patterns = IgnorePattern::parse_file("/out\n*.o\n", "src");
+        assert!(patterns[0].matches("src/out", false));
+        assert!(!patterns[0].matches("out", false));
+        assert!(!patterns[0].matches("src/a/out", false));
+        assert!(patterns[1].matches("src/a/b.o", false));
+        assert!(!patterns[1].matches("b.o", false));
+        assert!(!patterns[1].matches("srcx/b.o", false));
+    }
+
+    /// The entries of the fixture repository's index, as (path, mode, contents). The files in
+    /// `testdata` are that index as git 2.39 writes it with `update-index --index-version`,
+    /// `--split-index`, and after `sparse-checkout set --sparse-index`.
+    const FIXTURE: [(&str, u32, &[u8]); 4] = [
+        ("a.txt", 0o100644, b"alpha\n"),
+        ("dir/b.txt", 0o100755, b"beta\n"),
+        ("dir/sub/c.txt", 0o100644, b"gamma\n"),
+        ("link", 0o120000, b"a.txt"),
+    ];
+
+    fn check_index(entries: &[IndexEntry], expected: &[(&str, u32, &[u8])]) {
+        let found: Vec<_> = entries.iter().map(|e| (e.path.as_str(), e.mode, e.id, e.size, e.stage)).collect();
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(path, mode, contents)| (path, mode, blob_id(contents), contents.len() as u32, 0))
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn index_v2() {
+        let entries = read_index(include_bytes!("testdata/index-v2")).unwrap();
+        check_index(&entries, &FIXTURE);
+    }
+
+    #[test]
+    fn index_v3_extended_flags() {
+        // `new.txt` was added with `git add -N`, which needs the extended flags of version 3.
+        let entries = read_index(include_bytes!("testdata/index-v3")).unwrap();
+        let mut expected = FIXTURE.to_vec();
+        expected.push(("new.txt", 0o100644, b""));
+        check_index(&entries, &expected);
+    }
+
+    #[test]
+    fn index_v4_prefix_compression() {
+        let entries = read_index(include_bytes!("testdata/index-v4")).unwrap();
+        let mut expected = FIXTURE.to_vec();
+        expected.push(("new.txt", 0o100644, b""));
+        check_index(&entries, &expected);
+    }
+
+    #[test]
+    fn index_unsupported_formats() {
+        assert_eq!(
+            read_index(include_bytes!("testdata/index-split")).unwrap_err(),
+            IndexError::Unsupported("split index")
+        );
+        assert_eq!(
+            read_index(include_bytes!("testdata/index-sparse")).unwrap_err(),
+            IndexError::Unsupported("sparse index")
+        );
+    }
+
+    #[test]
+    fn index_malformed() {
+        let data = include_bytes!("testdata/index-v2");
+        assert_eq!(read_index(b"").unwrap_err(), IndexError::Malformed);
+        assert_eq!(read_index(b"DIRX\0\0\0\x02\0\0\0\0").unwrap_err(), IndexError::Malformed);
+        assert_eq!(read_index(&data[..100]).unwrap_err(), IndexError::Malformed);
+    }
+
+    #[test]
+    fn index_malformed_or_missing() {
+        let dir = std::env::temp_dir().join(format!("lslong-git-{}", std::process::id()));
+        let git_dir = dir.join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(dir.join("file"), "").unwrap();
+        let status = GitStatus::load(dir.clone(), &git_dir).unwrap();
+        assert_eq!(status.status("file", false), *b"??");
+        fs::write(git_dir.join("index"), &include_bytes!("testdata/index-v2")[..100]).unwrap();
+        assert!(GitStatus::load(dir.clone(), &git_dir).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_statuses() {
+        let paths: HashMap<String, [u8; 2]> = [
+            ("a/staged", *b"A "),
+            ("a/b/modified", *b" M"),
+            ("a/b/new", *b"??"),
+            ("c/new", *b"??"),
+            ("d/ignored", *b"!!"),
+            ("e/conflict", *b"UU"),
+            ("e/f/deleted", *b"D "),
+        ]
+        .into_iter()
+        .map(|(path, status)| (path.to_string(), status))
+        .collect();
+        let tracked_dirs = ["a", "a/b", "d", "e", "e/f"].into_iter().map(String::from).collect();
+        let status = GitStatus { workdir: PathBuf::new(), dirs: dir_statuses(&paths), paths, tracked_dirs };
+        let expected: [(&str, &[u8; 2]); 8] = [
+            ("", b"UU"),
+            ("a", b"AM"),
+            ("a/b", b" M"),
+            ("c", b"??"),
+            ("d", b"  "),
+            ("e/f", b"D "),
+            ("e/f/deleted", b"D "),
+            ("e/f/clean", b"  "),
+        ];
+        for (path, expected) in expected {
+            let is_dir = !path.contains("deleted") && !path.contains("clean");
+            assert_eq!(&status.status(path, is_dir), expected, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn delta_copy_and_insert() {
+        let base = b"hello, world";
+        // Sizes 12 and 13; copy 7 bytes from 0, insert "there", copy 1 byte from 5.
+        let delta = [12, 13, 0x91, 0, 7, 5, b't', b'h', b'e', b'r', b'e', 0x91, 5, 1];
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"hello, there,");
+        let delta = [12, 12, 0x91, 0, 7, 5, b't', b'h', b'e', b'r', b'e'];
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"hello, there");
+    }
+
+    #[test]
+    fn delta_copy_encodings() {
+        // Multi-byte offsets and sizes, and a size of 0 meaning 0x10000.
+        let base: Vec<u8> = (0..0x20000).map(|i| (i % 251) as u8).collect();
+        let delta = [0x80, 0x80, 0x08, 0x90, 0x02, 0xb3, 0x34, 0x12, 0x10, 0x01];
+        assert_eq!(apply_delta(&base, &delta).unwrap(), &base[0x1234..0x1234 + 0x110]);
+        let delta = [0x80, 0x80, 0x08, 0x80, 0x80, 0x04, 0x81, 0x10];
+        assert_eq!(apply_delta(&base, &delta).unwrap(), &base[0x10..0x10010]);
+    }
+
+    #[test]
+    fn delta_errors() {
+        let base = b"hello";
+        // The result isn't the size the header promised.
+        assert_eq!(apply_delta(base, &[5, 4, 0x91, 0, 5]), None);
+        // A copy past the end of the base.
+        assert_eq!(apply_delta(base, &[5, 6, 0x91, 0, 6]), None);
+        // An insert past the end of the delta.
+        assert_eq!(apply_delta(base, &[5, 3, 3, b'a']), None);
+        // Opcode 0 is reserved.
+        assert_eq!(apply_delta(base, &[5, 0, 0]), None);
+        // A truncated header.
+        assert_eq!(apply_delta(base, &[0x85]), None);
+    }
+}
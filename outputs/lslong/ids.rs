@@ -0,0 +1,190 @@
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::options::Options;
+
+/// Parses `/etc/passwd`- or `/etc/group`-format text into an id -> name map. Both formats keep
+/// the name in the first field and the id in the third; malformed lines are skipped. When an
+/// id appears twice, the first name wins, as with `getpwuid`.
+fn parse_id_file(contents: &str) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for line in contents.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(':').collect();
+        if let (Some(name), Some(Ok(id))) = (fields.first(), fields.get(2).map(|id| id.parse()))
+            && !name.is_empty()
+        {
+            names.entry(id).or_insert_with(|| name.to_string());
+        }
+    }
+    names
+}
+
+fn read_id_file(path: &Path) -> HashMap<u32, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse_id_file(&contents),
+        Err(e) => {
+            eprintln!("lslong: {}: {}", path.display(), e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Turns uids and gids into names, looking each id up only once.
+///
+/// Names come from NSS (via the `users` crate) unless `--passwd-file`/`--group-file` point at
+/// files in passwd/group format, which are parsed directly. NSS misses also fall back to
+/// parsing `/etc/passwd` and `/etc/group`, so static builds without NSS still show names.
+pub struct IdResolver {
+    numeric: bool,
+    passwd_file: Option<PathBuf>,
+    group_file: Option<PathBuf>,
+    users: RefCell<HashMap<u32, String>>,
+    groups: RefCell<HashMap<u32, String>>,
+    file_users: OnceCell<HashMap<u32, String>>,
+    file_groups: OnceCell<HashMap<u32, String>>,
+}
+
+impl IdResolver {
+    pub fn new(opts: &Options) -> Self {
+        IdResolver {
+            numeric: opts.numeric_ids,
+            passwd_file: opts.passwd_file.clone(),
+            group_file: opts.group_file.clone(),
+            users: RefCell::new(HashMap::new()),
+            groups: RefCell::new(HashMap::new()),
+            file_users: OnceCell::new(),
+            file_groups: OnceCell::new(),
+        }
+    }
+
+    pub fn uid_str(&self, uid: u32) -> String {
+        if self.numeric {
+            return uid.to_string();
+        }
+        self.users
+            .borrow_mut()
+            .entry(uid)
+            .or_insert_with(|| {
+                let from_nss = || users::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().into_owned());
+                let from_file = || {
+                    let path = self.passwd_file.as_deref().unwrap_or(Path::new("/etc/passwd"));
+                    self.file_users.get_or_init(|| read_id_file(path)).get(&uid).cloned()
+                };
+                let name = if self.passwd_file.is_some() { from_file() } else { from_nss().or_else(from_file) };
+                name.unwrap_or_else(|| "Unknown".to_string())
+            })
+            .clone()
+    }
+
+    pub fn gid_str(&self, gid: u32) -> String {
+        if self.numeric {
+            return gid.to_string();
+        }
+        self.groups
+            .borrow_mut()
+            .entry(gid)
+            .or_insert_with(|| {
+                let from_nss = || users::get_group_by_gid(gid).map(|g| g.name().to_string_lossy().into_owned());
+                let from_file = || {
+                    let path = self.group_file.as_deref().unwrap_or(Path::new("/etc/group"));
+                    self.file_groups.get_or_init(|| read_id_file(path)).get(&gid).cloned()
+                };
+                let name = if self.group_file.is_some() { from_file() } else { from_nss().or_else(from_file) };
+                name.unwrap_or_else(|| "Unknown".to_string())
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::lslong::parse_args;
+
+    const PASSWD: &str = "\
+# /etc/passwd for tests
+root:x:0:0:root:/root:/bin/bash
+
+daemon:x:1:1:daemon:/usr/sbin:/usr/sbin/nologin
+alice:x:1000:1000:Alice,,,:/home/alice:/bin/sh
+toor:x:0:0:second root:/root:/bin/sh
+broken line without fields
+nouid:x::100::/:/bin/sh
+badid:x:12a:100::/:/bin/sh
+:x:1001:1001:no name:/:/bin/sh
+svc.web-01:x:2000:2000::/srv:/usr/sbin/nologin
+ACME\\bob:x:2001:2001::/home/bob:/bin/sh
+josé:x:2002:2002::/home/jose:/bin/sh
+$machine$:x:2003:2003::/:/usr/sbin/nologin
+big:x:4294967294:100::/:/bin/sh
+toobig:x:4294967296:100::/:/bin/sh
+";
+
+    #[test]
+    fn parses_passwd_lines() {
+        let names = parse_id_file(PASSWD);
+        assert_eq!(names.get(&1).map(String::as_str), Some("daemon"));
+        assert_eq!(names.get(&1000).map(String::as_str), Some("alice"));
+        assert_eq!(names.get(&4294967294).map(String::as_str), Some("big"));
+    }
+
+    #[test]
+    fn first_name_for_an_id_wins() {
+        assert_eq!(parse_id_file(PASSWD).get(&0).map(String::as_str), Some("root"));
+    }
+
+    #[test]
+    fn skips_comments_blank_and_malformed_lines() {
+        let names = parse_id_file(PASSWD);
+        assert!(!names.values().any(|name| name.starts_with('#') || name.starts_with("broken")));
+        assert!(!names.values().any(|name| ["nouid", "badid", "toobig", ""].contains(&name.as_str())));
+        assert!(!names.contains_key(&1001));
+        assert_eq!(names.len(), 8);
+    }
+
+    #[test]
+    fn keeps_unusual_names_verbatim() {
+        let names = parse_id_file(PASSWD);
+        assert_eq!(names[&2000], "svc.web-01");
+        assert_eq!(names[&2001], "ACME\\bob");
+        assert_eq!(names[&2002], "josé");
+        assert_eq!(names[&2003], "$machine$");
+    }
+
+    #[test]
+    fn parses_group_lines() {
+        let names = parse_id_file("root:x:0:\nwheel:x:10:root,alice\n#staff:x:50:\nusers:*:100:\n");
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[&10], "wheel");
+        assert_eq!(names[&100], "users");
+    }
+
+    #[test]
+    fn resolves_from_passwd_and_group_files() {
+        let dir = std::env::temp_dir().join(format!("lslong-ids-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (passwd, group) = (dir.join("passwd"), dir.join("group"));
+        fs::write(&passwd, PASSWD).unwrap();
+        fs::write(&group, "fixture:x:4242:alice\n").unwrap();
+
+        let args = [format!("--passwd-file={}", passwd.display()), format!("--group-file={}", group.display())];
+        let (opts, _) = parse_args(args.into_iter()).unwrap();
+        let ids = IdResolver::new(&opts);
+        assert_eq!(ids.uid_str(2002), "josé");
+        assert_eq!(ids.uid_str(0), "root");
+        assert_eq!(ids.uid_str(31337), "Unknown");
+        assert_eq!(ids.gid_str(4242), "fixture");
+        assert_eq!(ids.gid_str(0), "Unknown");
+
+        let (opts, _) = parse_args(["-n".to_string()].into_iter()).unwrap();
+        assert_eq!(IdResolver::new(&opts).uid_str(2002), "2002");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
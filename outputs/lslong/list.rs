@@ -0,0 +1,425 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
+
+use super::entry::{Entry, stat_entry};
+use super::options::{Options, SortKey};
+
+/// Why a directory couldn't be listed.
+#[derive(Debug)]
+pub enum ListError {
+    /// The directory itself couldn't be read.
+    Open { path: PathBuf, source: io::Error },
+    /// Reading its entries failed partway through.
+    ReadDir { path: PathBuf, source: io::Error },
+    /// One of its entries couldn't be stat'd.
+    Stat { path: PathBuf, source: io::Error },
+    /// A file's contents couldn't be read, as for `--hash`.
+    Read { path: PathBuf, source: io::Error },
+}
+
+impl ListError {
+    pub fn path(&self) -> &Path {
+        match self {
+            ListError::Open { path, .. } | ListError::ReadDir { path, .. } => path,
+            ListError::Stat { path, .. } | ListError::Read { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListError::Open { path, source } => {
+                write!(f, "cannot open directory '{}': {}", path.display(), source)
+            }
+            ListError::ReadDir { path, source } => {
+                write!(f, "reading directory '{}': {}", path.display(), source)
+            }
+            ListError::Stat { path, source } => write!(f, "cannot access '{}': {}", path.display(), source),
+            ListError::Read { path, source } => write!(f, "cannot read '{}': {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for ListError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ListError::Open { source, .. } | ListError::ReadDir { source, .. } => Some(source),
+            ListError::Stat { source, .. } | ListError::Read { source, .. } => Some(source),
+        }
+    }
+}
+
+/// Reads, stats and sorts the entries of `path` that `-a`/`-A` say to show. The filter
+/// expression is left to [`is_selected`], since `-R` still descends into directories that
+/// fail it. Entries that can't be read or stat'd are left out; [`list_dir_reporting`] says
+/// which.
+pub fn list_dir(path: impl AsRef<Path>, opts: &Options) -> Result<Vec<Entry>, ListError> {
+    list_dir_reporting(path, opts, |_| {})
+}
+
+/// Like [`list_dir`], passing `report` an error for each entry that couldn't be read or
+/// stat'd, as `ls` reports them and carries on with the rest. Entries deleted while the
+/// directory is being read aren't errors.
+pub fn list_dir_reporting(
+    path: impl AsRef<Path>,
+    opts: &Options,
+    mut report: impl FnMut(ListError),
+) -> Result<Vec<Entry>, ListError> {
+    let path = path.as_ref();
+    let read = fs::read_dir(path).map_err(|source| ListError::Open { path: path.to_path_buf(), source })?;
+
+    // `read_dir` skips `.` and `..`, which the C original's `readdir` returned; put them back
+    // for `-a` so the listing matches.
+    let mut names: Vec<String> = Vec::new();
+    if opts.all {
+        names.push(".".to_string());
+        names.push("..".to_string());
+    }
+    for entry in read {
+        match entry {
+            Ok(entry) => {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if is_shown(&name, opts) {
+                    names.push(name);
+                }
+            }
+            Err(source) => report(ListError::ReadDir { path: path.to_path_buf(), source }),
+        }
+    }
+
+    let mut entries = Vec::with_capacity(names.len());
+    for (name, stat) in names.iter().zip(stat_all(path, &names, opts)) {
+        match stat {
+            Ok(entry) => entries.push(entry),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(source) => report(ListError::Stat { path: path.join(name), source }),
+        }
+    }
+    sort_entries(&mut entries, opts);
+    Ok(entries)
+}
+
+/// Stats `names` inside `dir`, on `--parallel` worker threads when asked, returning the
+/// results in the same order.
+fn stat_all(dir: &Path, names: &[String], opts: &Options) -> Vec<io::Result<Entry>> {
+    let jobs = opts.jobs.min(names.len());
+    if jobs <= 1 {
+        return names.iter().map(|name| stat_entry(dir, name, opts)).collect();
+    }
+
+    // Workers claim one index at a time, so a few slow stats can't hold up a whole chunk.
+    let next = AtomicUsize::new(0);
+    let mut stats: Vec<Option<io::Result<Entry>>> = names.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                        let Some(name) = names.get(i) else {
+                            break done;
+                        };
+                        done.push((i, stat_entry(dir, name, opts)));
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (i, stat) in worker.join().expect("stat worker panicked") {
+                stats[i] = Some(stat);
+            }
+        }
+    });
+    stats.into_iter().map(|stat| stat.expect("every name is stat'd")).collect()
+}
+
+/// Compares names by the current locale's collation order, as `ls` does.
+fn collate(a: &str, b: &str) -> Ordering {
+    match (CString::new(a), CString::new(b)) {
+        (Ok(a), Ok(b)) => unsafe { libc::strcoll(a.as_ptr(), b.as_ptr()) }.cmp(&0),
+        _ => a.cmp(b),
+    }
+}
+
+/// Natural ordering where runs of digits compare by numeric value, like `ls -v`.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() && !b.is_empty() {
+        if a[0].is_ascii_digit() && b[0].is_ascii_digit() {
+            let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+            let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+            let a_num = trim_zeros(&a[..a_len]);
+            let b_num = trim_zeros(&b[..b_len]);
+            let ord = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            a = &a[a_len..];
+            b = &b[b_len..];
+        } else {
+            if a[0] != b[0] {
+                return a[0].cmp(&b[0]);
+            }
+            a = &a[1..];
+            b = &b[1..];
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8] {
+    let zeros = digits.iter().take_while(|&&c| c == b'0').count();
+    &digits[zeros..]
+}
+
+fn extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(0) | None => "",
+        Some(pos) => &name[pos + 1..],
+    }
+}
+
+/// Whether this is a directory, or a symlink to one, for `--group-directories-first`.
+fn is_dir_like(entry: &Entry) -> bool {
+    if entry.is_symlink() { entry.path.is_dir() } else { entry.is_dir() }
+}
+
+/// Sorts entries by the `-t`/`-S`/`-X`/`-v` key, then by name, honouring `-r` and
+/// `--group-directories-first`.
+pub fn sort_entries(entries: &mut [Entry], opts: &Options) {
+    let time = |e: &Entry| e.time(opts.time_field);
+
+    entries.sort_by(|a, b| {
+        let ord = match opts.sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Time => time(b).cmp(&time(a)),
+            SortKey::Size => b.size.cmp(&a.size),
+            SortKey::Extension => collate(extension(&a.name), extension(&b.name)),
+            SortKey::Version => version_cmp(&a.name, &b.name),
+        };
+        let ord = ord.then_with(|| collate(&a.name, &b.name));
+        if opts.reverse { ord.reverse() } else { ord }
+    });
+
+    // Checking a symlink's target is a stat, so it's done once per entry, not per comparison.
+    if opts.group_directories_first {
+        entries.sort_by_cached_key(|e| !is_dir_like(e));
+    }
+}
+
+/// Whether an entry passes the filter expression, if there is one. Names are tested without
+/// their directory, as find's `-name` does.
+pub fn is_selected(entry: &Entry, opts: &Options) -> bool {
+    let Some(filter) = &opts.filter else {
+        return true;
+    };
+    let name = match Path::new(&entry.name).file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => entry.name.as_str().into(),
+    };
+    filter.matches(&name, entry)
+}
+
+/// Whether a name from `read_dir` should be listed. Dotfiles need `-a` or `-A`.
+fn is_shown(name: &str, opts: &Options) -> bool {
+    !name.starts_with('.') || opts.all || opts.almost_all
+}
+
+/// Groups hard links to the same file, for `--dedupe-hardlinks`: each group is in the place
+/// of its first name, which comes first in the group. Directories are never grouped.
+pub fn group_hardlinks<'a>(entries: &[&'a Entry]) -> Vec<Vec<&'a Entry>> {
+    let mut groups: Vec<Vec<&Entry>> = Vec::new();
+    let mut by_inode: HashMap<(u64, u64), usize> = HashMap::new();
+    for &entry in entries {
+        if entry.nlink > 1 && !entry.is_dir() {
+            if let Some(&group) = by_inode.get(&(entry.device, entry.inode)) {
+                groups[group].push(entry);
+                continue;
+            }
+            by_inode.insert((entry.device, entry.inode), groups.len());
+        }
+        groups.push(vec![entry]);
+    }
+    groups
+}
+
+/// Apparent size against space allocated on disk, summed over a `-R` listing. Each file is
+/// counted once however many hard links it has, as du does.
+#[derive(Debug, Default)]
+pub struct DiskUsage {
+    /// The total length of the files.
+    pub apparent: u64,
+    /// The total space allocated to them, `st_blocks * 512`.
+    pub allocated: u64,
+    /// How many of them are sparse, by [`Entry::is_sparse`].
+    pub sparse: usize,
+    seen: HashSet<(u64, u64)>,
+}
+
+impl DiskUsage {
+    pub fn add(&mut self, entry: &Entry) {
+        if entry.name == "." || entry.name == ".." || !self.seen.insert((entry.device, entry.inode)) {
+            return;
+        }
+        self.apparent += entry.size;
+        self.allocated += entry.blocks * 512;
+        self.sparse += entry.is_sparse() as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lslong::parse_args;
+
+    fn sorted(args: &[&str], names: &[&str]) -> Vec<String> {
+        let (opts, _) = parse_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let info = fs::symlink_metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        let mut entries: Vec<Entry> =
+            names.iter().map(|&name| Entry::from_metadata(name, PathBuf::from(name), &info)).collect();
+        sort_entries(&mut entries, &opts);
+        entries.into_iter().map(|entry| entry.name).collect()
+    }
+
+    #[test]
+    fn parallel_stats_keep_their_order() {
+        let dir = std::env::temp_dir().join(format!("lslong-parallel-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut names = Vec::new();
+        for i in 0..500 {
+            let name = format!("file{}", i);
+            fs::write(dir.join(&name), "x".repeat(i)).unwrap();
+            names.push(name);
+        }
+        // Errors scattered through the list: a name that's gone, and paths through a file.
+        for (i, name) in [(0, "missing"), (137, "file1/below"), (499, "missing-too"), (250, "file2/below")] {
+            names.insert(i, name.to_string());
+        }
+
+        let options = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string())).unwrap().0;
+        let sequential = stat_all(&dir, &names, &options(&[]));
+        let parallel = stat_all(&dir, &names, &options(&["--parallel=8"]));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(parallel.len(), names.len());
+        for ((name, one), many) in names.iter().zip(&sequential).zip(&parallel) {
+            match (one, many) {
+                (Ok(one), Ok(many)) => {
+                    assert_eq!((&many.name, many.inode, many.size), (name, one.inode, one.size));
+                    let i: usize = name.strip_prefix("file").unwrap().parse().unwrap();
+                    assert_eq!(many.size, i as u64);
+                }
+                (Err(one), Err(many)) => assert_eq!(one.kind(), many.kind(), "{}", name),
+                _ => panic!("{}: {:?} then {:?}", name, one, many),
+            }
+        }
+        let failed: Vec<(&str, io::ErrorKind)> = names
+            .iter()
+            .zip(&parallel)
+            .filter_map(|(name, stat)| Some((name.as_str(), stat.as_ref().err()?.kind())))
+            .collect();
+        let expected = [
+            ("missing", io::ErrorKind::NotFound),
+            ("file1/below", io::ErrorKind::NotADirectory),
+            ("file2/below", io::ErrorKind::NotADirectory),
+            ("missing-too", io::ErrorKind::NotFound),
+        ];
+        assert_eq!(failed, expected);
+    }
+
+    /// A directory with `a`, `b` and `d` hard links to one file, and `c` another.
+    fn hardlinked(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lslong-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a"), "shared").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        fs::write(dir.join("c"), "alone").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("d")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hardlink_groups() {
+        let dir = hardlinked("hardlinks");
+        let entries = list_dir(&dir, &Options::default()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let entries: Vec<&Entry> = entries.iter().collect();
+        let groups: Vec<Vec<&str>> = group_hardlinks(&entries)
+            .iter()
+            .map(|group| group.iter().map(|entry| entry.name.as_str()).collect())
+            .collect();
+        // Directories have links of their own (`.` and `sub/..`) but are never grouped.
+        assert_eq!(groups, [vec!["a", "b", "d"], vec!["c"], vec!["sub"]]);
+    }
+
+    #[test]
+    fn disk_usage() {
+        let dir = hardlinked("usage");
+        fs::File::create(dir.join("sparse")).unwrap().set_len(1 << 20).unwrap();
+        fs::File::create(dir.join("small-hole")).unwrap().set_len(4096).unwrap();
+        fs::write(dir.join("dense"), vec![1; 128 * 1024]).unwrap();
+        let (opts, _) = parse_args(["-a".to_string()].into_iter()).unwrap();
+        let entries = list_dir(&dir, &opts).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let sparse: Vec<&str> = entries.iter().filter(|e| e.is_sparse()).map(|e| e.name.as_str()).collect();
+        assert_eq!(sparse, ["sparse"]);
+        let mut usage = DiskUsage::default();
+        // Hard links and entries seen twice count once; `.` and `..` not at all.
+        for entry in entries.iter().chain(&entries) {
+            usage.add(entry);
+        }
+        let sub = entries.iter().find(|e| e.name == "sub").unwrap();
+        assert_eq!(usage.apparent, 6 + 5 + (1 << 20) + 4096 + 128 * 1024 + sub.size);
+        let counted = ["a", "c", "dense", "small-hole", "sparse", "sub"];
+        let allocated: u64 =
+            entries.iter().filter(|e| counted.contains(&e.name.as_str())).map(|e| e.blocks * 512).sum();
+        assert_eq!(usage.allocated, allocated);
+        assert!(usage.allocated < usage.apparent / 2);
+        assert_eq!(usage.sparse, 1);
+    }
+
+    #[test]
+    fn version_order() {
+        let cases = [
+            ("file2", "file10", Ordering::Less),
+            ("file10", "file9", Ordering::Greater),
+            ("file007", "file7", Ordering::Equal),
+            ("file007", "file08", Ordering::Less),
+            ("v1.2.10", "v1.2.9", Ordering::Greater),
+            ("file", "file1", Ordering::Less),
+            ("file1", "file1a", Ordering::Less),
+            ("a99999999999999999999999", "a100000000000000000000000", Ordering::Less),
+            ("", "", Ordering::Equal),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(version_cmp(a, b), expected, "{} vs {}", a, b);
+            assert_eq!(version_cmp(b, a), expected.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    #[test]
+    fn version_sort() {
+        let names = ["file10", "file2", "file02", "file1", "file", "file1.txt"];
+        assert_eq!(sorted(&["-v"], &names), ["file", "file1", "file1.txt", "file02", "file2", "file10"]);
+        assert_eq!(sorted(&["-vr"], &names), ["file10", "file2", "file02", "file1.txt", "file1", "file"]);
+    }
+
+    #[test]
+    fn extension_sort() {
+        let names = ["b.txt", "a.rs", "Makefile", ".bashrc", "c.tar.gz", "a.txt", "README"];
+        let expected = [".bashrc", "Makefile", "README", "c.tar.gz", "a.rs", "a.txt", "b.txt"];
+        assert_eq!(sorted(&["-X"], &names), expected);
+        assert_eq!(extension(".bashrc"), "");
+        assert_eq!(extension("archive.tar.gz"), "gz");
+        assert_eq!(extension("trailing."), "");
+    }
+}
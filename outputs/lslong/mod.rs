@@ -0,0 +1,38 @@
+//! The listing logic behind the `lslong` binary: reading directories into typed [`Entry`]s,
+//! sorting and filtering them, and writing them through a [`Renderer`].
+//!
+//! ```no_run
+//! use translations::lslong::{self, Options};
+//!
+//! let entries = lslong::list_dir("/tmp", &Options::default()).unwrap();
+//! for entry in &entries {
+//!     println!("{}{} {}", entry.file_type.indicator(), entry.permissions, entry.name);
+//! }
+//! ```
+
+mod color;
+mod entry;
+mod filter;
+mod git;
+mod ids;
+mod list;
+mod options;
+mod record;
+mod render;
+mod snapshot;
+mod tree;
+mod watch;
+mod xattr;
+
+pub use color::ColorMode;
+pub use entry::{Entry, FileType, Permissions, stat_entry};
+pub use ids::IdResolver;
+pub use list::{DiskUsage, ListError, group_hardlinks, is_selected, list_dir, list_dir_reporting, sort_entries};
+pub use options::{OutputFormat, Options, SizeFormat, SortKey, TimeField, TimeStyle, USAGE, parse_args};
+pub use record::{Record, RecordRenderer};
+pub use render::{LongRenderer, Renderer};
+pub use snapshot::{
+    Difference, FieldChange, SnapshotEntry, SnapshotError, TreeSnapshot, compare_snapshots, print_differences,
+};
+pub use tree::{TreeNode, walk_tree};
+pub use watch::{Change, Inotify, POLL_INTERVAL, diff_snapshots, snapshot};
@@ -0,0 +1,288 @@
+use std::path::PathBuf;
+use std::thread;
+
+use chrono::format::{Item, StrftimeItems};
+
+use super::color::ColorMode;
+use super::filter::{Filter, FilterToken, TEST_OPTIONS, Test};
+
+/// The key entries are sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    #[default]
+    Name,
+    Time,
+    Size,
+    Extension,
+    Version,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SizeFormat {
+    #[default]
+    Bytes,
+    Human,
+    Si,
+}
+
+/// Which timestamp is shown and used by `-t`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum TimeField {
+    #[default]
+    Modified,
+    Accessed,
+    Changed,
+}
+
+/// How entries are written: `ls -l` text, or one record per entry for `--format`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Long,
+    Json,
+    Csv,
+    Ndjson,
+}
+
+/// The `--time-style` argument.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum TimeStyle {
+    #[default]
+    Locale,
+    FullIso,
+    LongIso,
+    Iso,
+    Format(String),
+}
+
+impl TimeStyle {
+    pub fn parse(style: &str) -> Result<TimeStyle, String> {
+        Ok(match style {
+            "full-iso" => TimeStyle::FullIso,
+            "long-iso" => TimeStyle::LongIso,
+            "iso" => TimeStyle::Iso,
+            "locale" => TimeStyle::Locale,
+            // chrono can only report a bad conversion by failing to format, so it's caught here.
+            _ => match style.strip_prefix('+') {
+                Some(format) if !StrftimeItems::new(format).any(|item| item == Item::Error) => {
+                    TimeStyle::Format(format.to_string())
+                }
+                _ => return Err(format!("invalid argument '{}' for '--time-style'", style)),
+            },
+        })
+    }
+}
+
+/// Command-line options controlling the listing.
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    pub recursive: bool,
+    pub all: bool,
+    pub almost_all: bool,
+    pub sort: SortKey,
+    pub reverse: bool,
+    pub group_directories_first: bool,
+    pub size_format: SizeFormat,
+    pub time_field: TimeField,
+    pub time_style: TimeStyle,
+    pub numeric_ids: bool,
+    /// `-i`: show each entry's inode number.
+    pub inode: bool,
+    /// `--dedupe-hardlinks`: list hard links to the same file once, with their other names.
+    pub dedupe_hardlinks: bool,
+    /// `-d`: list directory operands themselves rather than their contents.
+    pub directory: bool,
+    pub passwd_file: Option<PathBuf>,
+    pub group_file: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub context: bool,
+    pub xattrs: bool,
+    /// `--git`: show each entry's status in its enclosing git repository.
+    pub git: bool,
+    /// `--tree`, drawn with `--charset=ascii` glyphs when `ascii` is set.
+    pub tree: bool,
+    pub ascii: bool,
+    /// `--depth N`: how many levels below each operand `--tree` descends.
+    pub depth: Option<usize>,
+    /// `--du`: show each directory's size as the total of everything beneath it.
+    pub du: bool,
+    /// The find-style filter expression entries must pass to be listed, from [`parse_args`].
+    pub(crate) filter: Option<Filter>,
+    /// `--watch`: keep reporting changes to the directory, by polling with `--poll`.
+    pub watch: bool,
+    pub poll: bool,
+    /// `--snapshot FILE`: save the state of the tree, dotfiles and all, to FILE instead of
+    /// listing it, with content hashes when `hash` is set.
+    pub snapshot: Option<PathBuf>,
+    pub hash: bool,
+    /// `--diff`: compare the two snapshot files given as operands.
+    pub diff: bool,
+    /// Threads used to stat each directory's entries; 0 or 1 stats them in turn.
+    pub jobs: usize,
+    pub color: ColorMode,
+}
+
+pub const USAGE: &str = "Usage: lslong [-aAcdhilnrRStuvXZ] [--si] [--group-directories-first] \
+                     [--time-style=full-iso|long-iso|iso|+FORMAT] [--passwd-file=FILE] \
+                     [--group-file=FILE] [--format=long|json|csv|ndjson] \
+                     [--color[=auto|always|never]] [--xattrs] [--git] [--inode] [--dedupe-hardlinks] \
+                     [--parallel[=N]] \
+                     [--tree [--depth=N] [--charset=unicode|ascii] [--du]] \
+                     [--watch [--poll]] [--snapshot FILE [--hash]] [--diff BEFORE AFTER] \
+                     [--name GLOB] [--type f|d|l|p|s|c|b] [--size [+-]N[cwbkMGT]] \
+                     [--newer FILE] [--mtime [+-]DAYS] [--user NAME] [--perm [-/]MODE] \
+                     [--and|--or|--not|( EXPR )] [FILE]...";
+
+fn parse_depth(depth: &str) -> Result<usize, String> {
+    match depth.parse() {
+        Ok(depth) if depth > 0 => Ok(depth),
+        _ => Err(format!("invalid argument '{}' for '--depth'", depth)),
+    }
+}
+
+/// Splits the command line into options and operands.
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(Options, Vec<String>), String> {
+    let mut opts = Options::default();
+    let mut dirs = Vec::new();
+    let mut only_operands = false;
+    let mut filter = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if !only_operands && (arg == "(" || arg == ")") {
+            filter.push(if arg == "(" { FilterToken::Open } else { FilterToken::Close });
+            continue;
+        }
+        if only_operands || arg == "-" || !arg.starts_with('-') {
+            dirs.push(arg);
+            continue;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            let (option, inline) = match long.split_once('=') {
+                Some((option, value)) => (option, Some(value.to_string())),
+                None => (long, None),
+            };
+            if TEST_OPTIONS.contains(&option) {
+                let Some(value) = inline.or_else(|| args.next()) else {
+                    return Err(format!("option '--{}' requires an argument", option));
+                };
+                filter.push(FilterToken::Test(Test::parse(option, &value)?));
+                continue;
+            }
+            if let Some(style) = long.strip_prefix("time-style=") {
+                opts.time_style = TimeStyle::parse(style)?;
+                continue;
+            }
+            if let Some(path) = long.strip_prefix("passwd-file=") {
+                opts.passwd_file = Some(PathBuf::from(path));
+                continue;
+            }
+            if let Some(format) = long.strip_prefix("format=") {
+                opts.format = match format {
+                    "long" => OutputFormat::Long,
+                    "json" => OutputFormat::Json,
+                    "csv" => OutputFormat::Csv,
+                    "ndjson" => OutputFormat::Ndjson,
+                    _ => return Err(format!("invalid argument '{}' for '--format'", format)),
+                };
+                continue;
+            }
+            if let Some(jobs) = long.strip_prefix("parallel=") {
+                opts.jobs = match jobs.parse() {
+                    Ok(jobs) if jobs > 0 => jobs,
+                    _ => return Err(format!("invalid argument '{}' for '--parallel'", jobs)),
+                };
+                continue;
+            }
+            if let Some(depth) = long.strip_prefix("depth=") {
+                opts.depth = Some(parse_depth(depth)?);
+                continue;
+            }
+            if let Some(charset) = long.strip_prefix("charset=") {
+                opts.ascii = match charset {
+                    "unicode" | "utf8" | "utf-8" => false,
+                    "ascii" => true,
+                    _ => return Err(format!("invalid argument '{}' for '--charset'", charset)),
+                };
+                continue;
+            }
+            if let Some(when) = long.strip_prefix("color=") {
+                opts.color = ColorMode::parse(when)?;
+                continue;
+            }
+            if let Some(path) = long.strip_prefix("snapshot=") {
+                opts.snapshot = Some(PathBuf::from(path));
+                continue;
+            }
+            if let Some(path) = long.strip_prefix("group-file=") {
+                opts.group_file = Some(PathBuf::from(path));
+                continue;
+            }
+            match long {
+                "" => only_operands = true,
+                "all" => opts.all = true,
+                "almost-all" => opts.almost_all = true,
+                "recursive" => opts.recursive = true,
+                "reverse" => opts.reverse = true,
+                "group-directories-first" => opts.group_directories_first = true,
+                "human-readable" => opts.size_format = SizeFormat::Human,
+                "si" => opts.size_format = SizeFormat::Si,
+                "numeric-uid-gid" => opts.numeric_ids = true,
+                "inode" => opts.inode = true,
+                "dedupe-hardlinks" => opts.dedupe_hardlinks = true,
+                "directory" => opts.directory = true,
+                "color" => opts.color = ColorMode::Always,
+                "context" => opts.context = true,
+                "xattrs" => opts.xattrs = true,
+                "git" => opts.git = true,
+                "tree" => opts.tree = true,
+                "and" => filter.push(FilterToken::And),
+                "or" => filter.push(FilterToken::Or),
+                "not" => filter.push(FilterToken::Not),
+                "watch" => opts.watch = true,
+                "poll" => opts.poll = true,
+                "du" => opts.du = true,
+                "hash" => opts.hash = true,
+                "diff" => opts.diff = true,
+                "snapshot" => match args.next() {
+                    Some(path) => opts.snapshot = Some(PathBuf::from(path)),
+                    None => return Err("option '--snapshot' requires an argument".to_string()),
+                },
+                "depth" => match args.next() {
+                    Some(depth) => opts.depth = Some(parse_depth(&depth)?),
+                    None => return Err("option '--depth' requires an argument".to_string()),
+                },
+                "parallel" => opts.jobs = thread::available_parallelism().map_or(4, |n| n.get()),
+                _ => return Err(format!("unrecognized option '{}'", arg)),
+            }
+            continue;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'a' => opts.all = true,
+                'A' => opts.almost_all = true,
+                'c' => opts.time_field = TimeField::Changed,
+                'd' => opts.directory = true,
+                'h' => opts.size_format = SizeFormat::Human,
+                'i' => opts.inode = true,
+                'l' => {}
+                'n' => opts.numeric_ids = true,
+                'r' => opts.reverse = true,
+                'R' => opts.recursive = true,
+                'S' => opts.sort = SortKey::Size,
+                't' => opts.sort = SortKey::Time,
+                'u' => opts.time_field = TimeField::Accessed,
+                'v' => opts.sort = SortKey::Version,
+                'X' => opts.sort = SortKey::Extension,
+                'Z' => opts.context = true,
+                _ => return Err(format!("invalid option -- '{}'", flag)),
+            }
+        }
+    }
+
+    opts.filter = Filter::parse(filter)?;
+    if opts.watch && matches!(opts.format, OutputFormat::Json | OutputFormat::Csv) {
+        return Err("--watch writes --format=long or --format=ndjson".to_string());
+    }
+
+    Ok((opts, dirs))
+}
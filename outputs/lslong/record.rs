@@ -0,0 +1,218 @@
+use std::path::Path;
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::Serialize;
+
+use super::entry::Entry;
+use super::ids::IdResolver;
+use super::list::group_hardlinks;
+use super::options::{Options, OutputFormat};
+use super::render::Renderer;
+use super::tree::TreeNode;
+
+/// One entry as written by `--format=json|csv|ndjson`.
+#[derive(Debug, Serialize)]
+pub struct Record {
+    name: String,
+    path: String,
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    mode: u32,
+    mode_octal: String,
+    mode_string: String,
+    nlink: u64,
+    uid: u32,
+    user: String,
+    gid: u32,
+    group: String,
+    size: u64,
+    blocks: u64,
+    atime: String,
+    mtime: String,
+    ctime: String,
+    inode: u64,
+    device: u64,
+    symlink_target: Option<String>,
+    /// The file's other names in the listing, with `--dedupe-hardlinks`. Not written to CSV.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hardlinks: Vec<String>,
+}
+
+/// The CSV header, in the same order as `Record`'s fields.
+const CSV_HEADER: &str = "name,path,type,mode,mode_octal,mode_string,nlink,uid,user,gid,group,\
+                          size,blocks,atime,mtime,ctime,inode,device,symlink_target";
+
+fn rfc3339(time: DateTime<Local>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Nanos, false)
+}
+
+impl Record {
+    pub fn new(entry: &Entry, ids: &IdResolver) -> Record {
+        Record {
+            name: entry.name.clone(),
+            path: entry.path.to_string_lossy().into_owned(),
+            file_type: entry.file_type.name(),
+            mode: entry.permissions.mode(),
+            mode_octal: format!("{:04o}", entry.permissions.mode()),
+            mode_string: entry.mode_string(),
+            nlink: entry.nlink,
+            uid: entry.uid,
+            user: ids.uid_str(entry.uid),
+            gid: entry.gid,
+            group: ids.gid_str(entry.gid),
+            size: entry.size,
+            blocks: entry.blocks,
+            atime: rfc3339(entry.accessed),
+            mtime: rfc3339(entry.modified),
+            ctime: rfc3339(entry.changed),
+            inode: entry.inode,
+            device: entry.device,
+            symlink_target: entry.symlink_target.as_ref().map(|target| target.to_string_lossy().into_owned()),
+            hardlinks: Vec::new(),
+        }
+    }
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break (RFC 4180).
+pub(crate) fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn csv_line(record: &Record) -> String {
+    let fields = [
+        csv_field(&record.name),
+        csv_field(&record.path),
+        record.file_type.to_string(),
+        record.mode.to_string(),
+        record.mode_octal.clone(),
+        record.mode_string.clone(),
+        record.nlink.to_string(),
+        record.uid.to_string(),
+        csv_field(&record.user),
+        record.gid.to_string(),
+        csv_field(&record.group),
+        record.size.to_string(),
+        record.blocks.to_string(),
+        record.atime.clone(),
+        record.mtime.clone(),
+        record.ctime.clone(),
+        record.inode.to_string(),
+        record.device.to_string(),
+        record.symlink_target.as_deref().map(csv_field).unwrap_or_default(),
+    ];
+    fields.join(",")
+}
+
+/// Streams records to stdout in the chosen `--format`; JSON output is a single array across
+/// every directory listed.
+pub struct RecordRenderer {
+    format: OutputFormat,
+    ids: IdResolver,
+    written: usize,
+    dedupe: bool,
+}
+
+impl RecordRenderer {
+    pub fn new(opts: &Options) -> Self {
+        match opts.format {
+            OutputFormat::Json => print!("["),
+            OutputFormat::Csv => println!("{}", CSV_HEADER),
+            OutputFormat::Long | OutputFormat::Ndjson => {}
+        }
+        RecordRenderer {
+            format: opts.format,
+            ids: IdResolver::new(opts),
+            written: 0,
+            dedupe: opts.dedupe_hardlinks,
+        }
+    }
+
+    /// Writes one record per entry, or with `--dedupe-hardlinks` one per file.
+    fn write_all(&mut self, entries: &[&Entry]) {
+        if !self.dedupe {
+            entries.iter().for_each(|entry| self.write(entry, Vec::new()));
+            return;
+        }
+        for group in group_hardlinks(entries) {
+            let others = group[1..].iter().map(|entry| entry.name.clone()).collect();
+            self.write(group[0], others);
+        }
+    }
+
+    fn write(&mut self, entry: &Entry, hardlinks: Vec<String>) {
+        let record = Record { hardlinks, ..Record::new(entry, &self.ids) };
+        let first = self.written == 0;
+        self.written += 1;
+        match self.format {
+            OutputFormat::Json => {
+                let json = serde_json::to_string(&record).unwrap_or_default();
+                print!("{}\n  {}", if first { "" } else { "," }, json);
+            }
+            OutputFormat::Ndjson => println!("{}", serde_json::to_string(&record).unwrap_or_default()),
+            OutputFormat::Csv => println!("{}", csv_line(&record)),
+            OutputFormat::Long => {}
+        }
+    }
+}
+
+impl Renderer for RecordRenderer {
+    fn files(&mut self, entries: &[&Entry]) {
+        self.write_all(entries);
+    }
+
+    fn directory(&mut self, _path: &Path, entries: &[&Entry]) {
+        self.write_all(entries);
+    }
+
+    fn tree(&mut self, nodes: &[TreeNode]) {
+        nodes.iter().for_each(|node| self.write(&node.entry, Vec::new()));
+    }
+
+    fn finish(&mut self) {
+        if self.format == OutputFormat::Json {
+            if self.written > 0 {
+                println!();
+            }
+            println!("]");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn csv_quoting() {
+        let fields = [
+            ("plain.txt", "plain.txt"),
+            ("", ""),
+            ("a,b", "\"a,b\""),
+            ("say \"hi\"", "\"say \"\"hi\"\"\""),
+            ("two\nlines", "\"two\nlines\""),
+            ("cr\r", "\"cr\r\""),
+            ("\"", "\"\"\"\""),
+        ];
+        for (field, expected) in fields {
+            assert_eq!(csv_field(field), expected, "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn csv_lines_match_the_header() {
+        let info = fs::symlink_metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let mut entry = Entry::from_metadata("a,\"b\"\nc", "dir/a,\"b\"\nc".into(), &info);
+        entry.symlink_target = Some("x,y".into());
+        let line = csv_line(&Record::new(&entry, &IdResolver::new(&Options::default())));
+        assert!(line.starts_with("\"a,\"\"b\"\"\nc\",\"dir/a,\"\"b\"\"\nc\",directory,"), "{}", line);
+        assert!(line.ends_with(",\"x,y\""), "{}", line);
+        // Without the quoted fields, the rest splits into one column per header name.
+        let unquoted = line.split('"').step_by(2).collect::<String>();
+        assert_eq!(unquoted.split(',').count(), CSV_HEADER.split(',').count());
+    }
+}
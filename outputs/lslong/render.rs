@@ -0,0 +1,438 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+
+use super::color::Colors;
+use super::entry::Entry;
+use super::git::git_column;
+use super::ids::IdResolver;
+use super::list::{DiskUsage, group_hardlinks};
+use super::options::{Options, SizeFormat, TimeStyle};
+use super::tree::TreeNode;
+use super::xattr::{SELINUX_XATTR, get_xattr, list_xattrs, xattr_indicator, xattr_value_string};
+
+/// Writes listings. `lslong` picks a [`LongRenderer`] or a
+/// [`RecordRenderer`](super::RecordRenderer) by `--format`; other tools can supply their own.
+pub trait Renderer {
+    /// Writes the file operands, named as given on the command line.
+    fn files(&mut self, entries: &[&Entry]);
+
+    /// Writes the `path:` line that comes before a directory's listing with `-R` or several
+    /// operands.
+    fn heading(&mut self, _path: &Path) {}
+
+    /// Writes the entries of the directory `path`.
+    fn directory(&mut self, path: &Path, entries: &[&Entry]);
+
+    /// Writes a `--tree`, the operand first and then everything beneath it.
+    fn tree(&mut self, nodes: &[TreeNode]);
+
+    /// Writes the apparent size and disk usage of everything a `-R` listing went through.
+    fn usage(&mut self, _usage: &DiskUsage) {}
+
+    /// Finishes the output, such as closing a JSON array.
+    fn finish(&mut self) {}
+}
+
+/// The `ls -l` format, with the columns of each listing lined up.
+pub struct LongRenderer<'a> {
+    opts: &'a Options,
+    ids: IdResolver,
+    /// The `LS_COLORS` database, when `--color` applies.
+    colors: Option<Colors>,
+    /// Set once something is printed, so later listings are separated by a blank line.
+    started: bool,
+}
+
+impl<'a> LongRenderer<'a> {
+    pub fn new(opts: &'a Options) -> Self {
+        let colors = opts.color.enabled().then(Colors::from_env);
+        LongRenderer { opts, ids: IdResolver::new(opts), colors, started: false }
+    }
+
+    /// Clears the terminal, so the next listing starts at the top as if nothing came before.
+    pub fn clear_screen(&mut self) {
+        print!("\x1b[H\x1b[2J");
+        self.started = false;
+    }
+
+    /// The name column: the name, `-> target` for symlinks, and colors when enabled.
+    fn display_name(&self, entry: &Entry) -> String {
+        let name = &entry.name;
+        let Some(colors) = &self.colors else {
+            return match &entry.symlink_target {
+                Some(target) => format!("{} -> {}", name, target.display()),
+                None => name.to_string(),
+            };
+        };
+
+        let Some(target) = &entry.symlink_target else {
+            return colors.paint(colors.sequence(name, entry, None), name);
+        };
+        let target_name = target.to_string_lossy();
+        let target_entry = fs::metadata(&entry.path)
+            .ok()
+            .map(|info| Entry::from_metadata(&target_name, entry.path.clone(), &info));
+        let target_sequence = match &target_entry {
+            Some(target_entry) => colors.sequence(&target_name, target_entry, None),
+            None => colors.get("mi"),
+        };
+        format!(
+            "{} -> {}",
+            colors.paint(colors.sequence(name, entry, target_entry.as_ref()), name),
+            colors.paint(target_sequence, &target_name)
+        )
+    }
+
+    /// The entries to show a row for: with `--dedupe-hardlinks`, one group per file with the
+    /// name it's listed under first.
+    fn groups<'e>(&self, entries: &[&'e Entry]) -> Vec<Vec<&'e Entry>> {
+        if self.opts.dedupe_hardlinks {
+            group_hardlinks(entries)
+        } else {
+            entries.iter().map(|&entry| vec![entry]).collect()
+        }
+    }
+
+    fn rows(&self, groups: &[Vec<&Entry>]) -> Vec<Row> {
+        groups
+            .iter()
+            .map(|group| {
+                let mut row = self.row(group[0]);
+                if group.len() > 1 {
+                    let others: Vec<&str> = group[1..].iter().map(|entry| entry.name.as_str()).collect();
+                    row.name = format!("{}  [also: {}]", row.name, others.join(", "));
+                }
+                row
+            })
+            .collect()
+    }
+
+    fn row(&self, entry: &Entry) -> Row {
+        let opts = self.opts;
+        let path = &entry.path;
+        // The names are needed whatever the options, since the `+`/`.` indicator is part of the
+        // mode column; values are only read for `-Z` and `--xattrs`.
+        let xattr_names = list_xattrs(path);
+        let has_context = xattr_names.iter().any(|name| name == SELINUX_XATTR);
+        let context = opts.context.then(|| match has_context.then(|| get_xattr(path, SELINUX_XATTR)).flatten() {
+            Some(value) => String::from_utf8_lossy(value.strip_suffix(&[0]).unwrap_or(&value)).into_owned(),
+            None => "?".to_string(),
+        });
+        let xattrs = if opts.xattrs {
+            xattr_names
+                .iter()
+                .map(|name| match get_xattr(path, name) {
+                    Some(value) => format!("{}={}", name, xattr_value_string(&value)),
+                    None => name.clone(),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut name = self.display_name(entry);
+        if opts.recursive && entry.is_sparse() {
+            name.push_str("  [sparse]");
+        }
+        Row {
+            inode: opts.inode.then(|| entry.inode.to_string()),
+            mode: entry.mode_string() + xattr_indicator(&xattr_names),
+            nlink: entry.nlink.to_string(),
+            user: self.ids.uid_str(entry.uid),
+            group: self.ids.gid_str(entry.gid),
+            context,
+            size: size_to_string(entry.size, opts.size_format),
+            time: time_to_string(entry.time(opts.time_field), &opts.time_style),
+            git: opts.git.then(|| git_column(entry)),
+            name,
+            xattrs,
+        }
+    }
+
+    fn print(&mut self, rows: &[Row]) {
+        self.started |= !rows.is_empty();
+        print!("{}", format_rows(rows));
+    }
+}
+
+impl Renderer for LongRenderer<'_> {
+    fn files(&mut self, entries: &[&Entry]) {
+        let rows = self.rows(&self.groups(entries));
+        self.print(&rows);
+    }
+
+    fn heading(&mut self, path: &Path) {
+        if self.started {
+            println!();
+        }
+        println!("{}:", path.display());
+        self.started = true;
+    }
+
+    fn directory(&mut self, _path: &Path, entries: &[&Entry]) {
+        let groups = self.groups(entries);
+        let files: Vec<&Entry> = groups.iter().map(|group| group[0]).collect();
+        println!("{}", total_line(&files, self.opts));
+        self.started = true;
+        let rows = self.rows(&groups);
+        self.print(&rows);
+    }
+
+    fn tree(&mut self, nodes: &[TreeNode]) {
+        if self.started {
+            println!();
+        }
+        let rows: Vec<Row> = nodes
+            .iter()
+            .map(|node| {
+                let mut row = self.row(&node.entry);
+                row.name = format!("{}{}", node.prefix, row.name);
+                if let Some(note) = node.note {
+                    row.name = format!("{}  {}", row.name, note);
+                }
+                if self.opts.du && node.entry.is_dir() {
+                    row.size = size_to_string(node.size, self.opts.size_format);
+                }
+                row
+            })
+            .collect();
+        self.print(&rows);
+    }
+
+    fn usage(&mut self, usage: &DiskUsage) {
+        let format = self.opts.size_format;
+        let sparse = match usage.sparse {
+            0 => String::new(),
+            1 => ", 1 sparse file".to_string(),
+            n => format!(", {} sparse files", n),
+        };
+        println!();
+        println!(
+            "apparent size {}, disk usage {}{}",
+            size_to_string(usage.apparent, format),
+            size_to_string(usage.allocated, format),
+            sparse
+        );
+    }
+}
+
+/// The formatted columns of one line of output.
+struct Row {
+    /// The inode number, with `-i`.
+    inode: Option<String>,
+    mode: String,
+    nlink: String,
+    user: String,
+    group: String,
+    /// The SELinux context, with `-Z`.
+    context: Option<String>,
+    size: String,
+    time: String,
+    /// The two-character repository status, with `--git`.
+    git: Option<String>,
+    name: String,
+    /// `name="value"` lines printed under the row, with `--xattrs`.
+    xattrs: Vec<String>,
+}
+
+/// Lays out rows with every column padded to its widest value, as `ls -l` does, one line each.
+fn format_rows(rows: &[Row]) -> String {
+    let width = |column: fn(&Row) -> &String| rows.iter().map(|r| column(r).chars().count()).max().unwrap_or(0);
+    // The mode column only grows to make room for `+`/`.` when some entry has one.
+    let mode_w = width(|r| &r.mode);
+    let context_w = rows.iter().filter_map(|r| r.context.as_ref()).map(|c| c.chars().count()).max().unwrap_or(0);
+    let nlink_w = width(|r| &r.nlink);
+    let user_w = width(|r| &r.user);
+    let group_w = width(|r| &r.group);
+    let size_w = width(|r| &r.size);
+    let time_w = width(|r| &r.time);
+    let inode_w = rows.iter().filter_map(|r| r.inode.as_ref()).map(|i| i.len()).max().unwrap_or(0);
+
+    let mut out = String::new();
+    for row in rows {
+        if let Some(inode) = &row.inode {
+            let _ = write!(out, "{:>inode_w$} ", inode);
+        }
+        let context = match &row.context {
+            Some(context) => format!("{:<context_w$} ", context),
+            None => String::new(),
+        };
+        let git = row.git.as_ref().map_or(String::new(), |git| format!("{} ", git));
+        let _ = writeln!(
+            out,
+            "{:<mode_w$} {:>nlink_w$} {:<user_w$} {:<group_w$} {}{:>size_w$} {:<time_w$} {}{}",
+            row.mode, row.nlink, row.user, row.group, context, row.size, row.time, git, row.name
+        );
+        for xattr in &row.xattrs {
+            let _ = writeln!(out, "    {}", xattr);
+        }
+    }
+    out
+}
+
+/// The `total` line: allocated space in 1K blocks, or human-readable with `-h`/`--si`.
+fn total_line(entries: &[&Entry], opts: &Options) -> String {
+    let kib: u64 = entries.iter().map(|entry| (entry.blocks * 512).div_ceil(1024)).sum();
+    match opts.size_format {
+        SizeFormat::Bytes => format!("total {}", kib),
+        format => format!("total {}", size_to_string(kib * 1024, format)),
+    }
+}
+
+/// Formats a size for `-h` (powers of 1024) or `--si` (powers of 1000), rounding up like GNU ls.
+fn size_to_string(bytes: u64, format: SizeFormat) -> String {
+    let (base, units) = match format {
+        SizeFormat::Bytes => return bytes.to_string(),
+        SizeFormat::Human => (1024.0, ["K", "M", "G", "T", "P", "E"]),
+        SizeFormat::Si => (1000.0, ["k", "M", "G", "T", "P", "E"]),
+    };
+
+    let mut value = bytes as f64;
+    if value < base {
+        return bytes.to_string();
+    }
+    let mut unit = 0;
+    value /= base;
+    while value >= base && unit + 1 < units.len() {
+        value /= base;
+        unit += 1;
+    }
+
+    let mut rounded = if value < 10.0 { (value * 10.0).ceil() / 10.0 } else { value.ceil() };
+    if rounded >= base && unit + 1 < units.len() {
+        rounded = 1.0;
+        unit += 1;
+    }
+    if rounded < 10.0 {
+        format!("{:.1}{}", rounded, units[unit])
+    } else {
+        format!("{:.0}{}", rounded, units[unit])
+    }
+}
+
+/// Files older than this (or from the future) show their year instead of the time of day.
+const SIX_MONTHS_SECS: i64 = 31_556_952 / 2;
+
+fn time_to_string(time: DateTime<Local>, style: &TimeStyle) -> String {
+    let age = Local::now().signed_duration_since(time).num_seconds();
+    let recent = (0..SIX_MONTHS_SECS).contains(&age);
+    match style {
+        TimeStyle::Locale if recent => time.format("%b %e %H:%M").to_string(),
+        TimeStyle::Locale => time.format("%b %e  %Y").to_string(),
+        TimeStyle::FullIso => time.format("%Y-%m-%d %H:%M:%S%.9f %z").to_string(),
+        TimeStyle::LongIso => time.format("%Y-%m-%d %H:%M").to_string(),
+        TimeStyle::Iso if recent => time.format("%m-%d %H:%M").to_string(),
+        TimeStyle::Iso => time.format("%Y-%m-%d ").to_string(),
+        TimeStyle::Format(format) => {
+            // As in GNU ls, `+OLD\nRECENT`-style formats pick by age.
+            let format = match format.split_once('\n') {
+                Some((old, new)) => if recent { new } else { old },
+                None => format,
+            };
+            time.format(format).to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use chrono::{Duration, TimeZone};
+
+    use crate::lslong::entry::Permissions;
+    use crate::lslong::parse_args;
+
+    #[test]
+    fn sizes() {
+        let cases = [
+            (0, "0", "0", "0"),
+            (999, "999", "999", "999"),
+            (1000, "1000", "1000", "1.0k"),
+            (1024, "1024", "1.0K", "1.1k"),
+            (1025, "1025", "1.1K", "1.1k"),
+            (10 * 1024, "10240", "10K", "11k"),
+            (1024 * 1024 - 1, "1048575", "1.0M", "1.1M"),
+            (5 * 1024 * 1024 * 1024, "5368709120", "5.0G", "5.4G"),
+            (u64::MAX, "18446744073709551615", "16E", "19E"),
+        ];
+        let formats = [SizeFormat::Bytes, SizeFormat::Human, SizeFormat::Si];
+        for (bytes, plain, human, si) in cases {
+            let sizes = formats.map(|format| size_to_string(bytes, format));
+            assert_eq!(sizes, [plain, human, si], "{bytes}");
+        }
+    }
+
+    fn style(style: &str) -> TimeStyle {
+        TimeStyle::parse(style).unwrap()
+    }
+
+    #[test]
+    fn old_times() {
+        let time = Local.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap();
+        let zone = time.format("%z").to_string();
+        assert_eq!(time_to_string(time, &TimeStyle::Locale), "Jan  2  2020");
+        assert_eq!(time_to_string(time, &style("iso")), "2020-01-02 ");
+        assert_eq!(time_to_string(time, &style("long-iso")), "2020-01-02 03:04");
+        assert_eq!(time_to_string(time, &style("full-iso")), format!("2020-01-02 03:04:05.000000000 {zone}"));
+        assert_eq!(time_to_string(time, &style("+%Y/%j %%")), "2020/002 %");
+        assert_eq!(time_to_string(time, &style("+old %Y\nnew %H")), "old 2020");
+    }
+
+    #[test]
+    fn recent_times() {
+        let time = Local::now() - Duration::hours(1);
+        assert_eq!(time_to_string(time, &TimeStyle::Locale), time.format("%b %e %H:%M").to_string());
+        assert_eq!(time_to_string(time, &style("iso")), time.format("%m-%d %H:%M").to_string());
+        assert_eq!(time_to_string(time, &style("long-iso")), time.format("%Y-%m-%d %H:%M").to_string());
+        assert_eq!(time_to_string(time, &style("+old %Y\nnew %H")), time.format("new %H").to_string());
+        // The future counts as old.
+        let time = Local::now() + Duration::days(1);
+        assert_eq!(time_to_string(time, &style("iso")), time.format("%Y-%m-%d ").to_string());
+    }
+
+    #[test]
+    fn bad_time_styles() {
+        for bad in ["+%Q", "+%Y %", "+ok\n%Q", "bogus", ""] {
+            let error = TimeStyle::parse(bad).unwrap_err();
+            assert_eq!(error, format!("invalid argument '{bad}' for '--time-style'"));
+        }
+        assert_eq!(style("+"), TimeStyle::Format(String::new()));
+    }
+
+    #[test]
+    fn columns_line_up() {
+        let info = fs::symlink_metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        let entry = |name: &str, mode, nlink, uid, gid, size, blocks| Entry {
+            // A path that doesn't exist, so no xattr indicator turns up.
+            path: PathBuf::from("/nonexistent/lslong").join(name),
+            permissions: Permissions::from_mode(mode),
+            nlink,
+            uid,
+            gid,
+            size,
+            blocks,
+            modified: Local.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap(),
+            ..Entry::from_metadata(name, PathBuf::new(), &info)
+        };
+        let a = entry("a", 0o644, 1, 0, 0, 5, 8);
+        let b = entry("bb", 0o755, 12, 1000, 100, 123456, 1);
+        let args = ["-n", "--time-style=long-iso"];
+        let (opts, _) = parse_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let renderer = LongRenderer::new(&opts);
+        let rows = renderer.rows(&renderer.groups(&[&a, &b]));
+        assert_eq!(
+            format_rows(&rows),
+            "-rw-r--r--  1 0    0        5 2020-01-02 03:04 a\n\
+             -rwxr-xr-x 12 1000 100 123456 2020-01-02 03:04 bb\n"
+        );
+        // 4K for a's eight blocks, and a whole 1K for b's one.
+        assert_eq!(total_line(&[&a, &b], &opts), "total 5");
+        let (opts, _) = parse_args(["-h".to_string()].into_iter()).unwrap();
+        assert_eq!(total_line(&[&a, &b], &opts), "total 5.0K");
+        assert_eq!(total_line(&[], &opts), "total 0");
+    }
+}
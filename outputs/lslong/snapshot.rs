@@ -0,0 +1,415 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+
+use super::entry::Entry;
+use super::ids::IdResolver;
+use super::list::{ListError, is_selected, list_dir_reporting};
+use super::options::{Options, OutputFormat};
+use super::record::csv_field;
+
+/// The state of a directory tree, as saved by `--snapshot` and compared by `--diff`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeSnapshot {
+    /// The directory as given on the command line.
+    pub root: String,
+    pub taken: String,
+    /// Whether regular files carry a content hash (`--hash`).
+    pub hashed: bool,
+    /// Whether dotfiles were recorded. They always are now, but older snapshots followed `-a`.
+    #[serde(default)]
+    pub hidden: bool,
+    /// `root` itself, as `.`, and everything beneath it, sorted by path.
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// One entry of a [`TreeSnapshot`]: the fields `--diff` compares.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    /// The path relative to the snapshot's root, so that copies of a tree can be compared.
+    pub path: String,
+    #[serde(rename = "type")]
+    pub file_type: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub user: String,
+    pub gid: u32,
+    pub group: String,
+    pub size: u64,
+    pub mtime: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+    /// The SHA-1 of a regular file's contents, with `--hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+}
+
+/// Why a snapshot couldn't be saved or loaded.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Read { path: PathBuf, source: io::Error },
+    Write { path: PathBuf, source: io::Error },
+    /// The file isn't JSON written by `--snapshot`.
+    Parse { path: PathBuf, source: serde_json::Error },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Read { path, source } => write!(f, "cannot read '{}': {}", path.display(), source),
+            SnapshotError::Write { path, source } => write!(f, "cannot write '{}': {}", path.display(), source),
+            SnapshotError::Parse { path, source } => {
+                write!(f, "'{}' is not a snapshot: {}", path.display(), source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Read { source, .. } | SnapshotError::Write { source, .. } => Some(source),
+            SnapshotError::Parse { source, .. } => Some(source),
+        }
+    }
+}
+
+impl TreeSnapshot {
+    /// Records `root` and everything beneath it that `-R -A` would list, hashing regular files
+    /// with `--hash`. Dotfiles are part of the tree's state, so they're recorded with or without
+    /// `-a`. Directories and files that can't be read are returned alongside and left out (or,
+    /// for contents, left unhashed).
+    pub fn take(root: &Path, opts: &Options) -> (TreeSnapshot, Vec<ListError>) {
+        let opts = &Options { almost_all: true, ..opts.clone() };
+        let ids = IdResolver::new(opts);
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        let mut ancestors = Vec::new();
+        let mut walk = Walk { root, opts, ids: &ids, entries: &mut entries, errors: &mut errors };
+        // The root's own mode and owner are part of the tree's state, but not its name.
+        if let Ok(info) = fs::metadata(root) {
+            ancestors.push((info.dev(), info.ino()));
+            let record = walk.record(&Entry::from_metadata(".", root.to_path_buf(), &info));
+            walk.entries.push(SnapshotEntry { path: ".".to_string(), ..record });
+        }
+        walk.dir(root, &mut ancestors);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let snapshot = TreeSnapshot {
+            root: root.to_string_lossy().into_owned(),
+            taken: Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false),
+            hashed: opts.hash,
+            hidden: true,
+            entries,
+        };
+        (snapshot, errors)
+    }
+
+    pub fn load(path: &Path) -> Result<TreeSnapshot, SnapshotError> {
+        let file = File::open(path).map_err(|source| SnapshotError::Read { path: path.to_path_buf(), source })?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|source| SnapshotError::Parse { path: path.to_path_buf(), source })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        let write_error = |source| SnapshotError::Write { path: path.to_path_buf(), source };
+        let mut out = BufWriter::new(File::create(path).map_err(write_error)?);
+        serde_json::to_writer_pretty(&mut out, self).map_err(|e| write_error(e.into()))?;
+        writeln!(out).and_then(|_| out.flush()).map_err(write_error)
+    }
+}
+
+/// The state shared by a snapshot's walk down the tree.
+struct Walk<'a> {
+    root: &'a Path,
+    opts: &'a Options,
+    ids: &'a IdResolver,
+    entries: &'a mut Vec<SnapshotEntry>,
+    errors: &'a mut Vec<ListError>,
+}
+
+impl Walk<'_> {
+    /// Records the entries of `dir` and descends into its subdirectories. As with `-R`,
+    /// `ancestors` keeps bind-mount loops from being walked forever; they're skipped quietly.
+    fn dir(&mut self, dir: &Path, ancestors: &mut Vec<(u64, u64)>) {
+        let entries = match list_dir_reporting(dir, self.opts, |e| self.errors.push(e)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.errors.push(e);
+                return;
+            }
+        };
+        for entry in &entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            if is_selected(entry, self.opts) {
+                let record = self.record(entry);
+                self.entries.push(record);
+            }
+            let id = (entry.device, entry.inode);
+            if entry.is_dir() && !ancestors.contains(&id) {
+                ancestors.push(id);
+                self.dir(&entry.path, ancestors);
+                ancestors.pop();
+            }
+        }
+    }
+
+    fn record(&mut self, entry: &Entry) -> SnapshotEntry {
+        let path = entry.path.strip_prefix(self.root).unwrap_or(&entry.path);
+        let sha1 = if self.opts.hash && entry.is_file() {
+            match file_sha1(&entry.path) {
+                Ok(sha1) => Some(sha1),
+                Err(source) => {
+                    self.errors.push(ListError::Read { path: entry.path.clone(), source });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        SnapshotEntry {
+            path: path.to_string_lossy().into_owned(),
+            file_type: entry.file_type.name().to_string(),
+            mode: entry.permissions.mode(),
+            uid: entry.uid,
+            user: self.ids.uid_str(entry.uid),
+            gid: entry.gid,
+            group: self.ids.gid_str(entry.gid),
+            size: entry.size,
+            mtime: entry.modified.to_rfc3339_opts(SecondsFormat::Nanos, false),
+            symlink_target: entry.symlink_target.as_ref().map(|target| target.to_string_lossy().into_owned()),
+            sha1,
+        }
+    }
+}
+
+/// The hex SHA-1 of a file's contents, read in chunks so large files aren't held in memory.
+fn file_sha1(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = sha1_smol::Sha1::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hasher.digest().to_string()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A field that differs between two snapshots of an entry, with both values as shown.
+#[derive(Debug, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+/// An entry that was added, removed or changed between two snapshots.
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub path: String,
+    /// `added`, `removed` or `changed`.
+    pub change: &'static str,
+    /// What changed, for `changed` entries.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldChange>,
+}
+
+/// Compares two snapshots entry by entry, in path order. Content hashes are only compared
+/// when both snapshots have one for the entry. Dotfiles missing from a snapshot that didn't
+/// record them show up as added or removed; see [`TreeSnapshot::hidden`].
+pub fn compare_snapshots(before: &TreeSnapshot, after: &TreeSnapshot) -> Vec<Difference> {
+    let before: BTreeMap<&str, &SnapshotEntry> = before.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    let after: BTreeMap<&str, &SnapshotEntry> = after.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut paths: Vec<&str> = before.keys().chain(after.keys()).copied().collect();
+    paths.sort_unstable();
+    paths.dedup();
+
+    let mut differences = Vec::new();
+    for path in paths {
+        let (change, fields) = match (before.get(path), after.get(path)) {
+            (None, Some(_)) => ("added", Vec::new()),
+            (Some(_), None) => ("removed", Vec::new()),
+            (Some(old), Some(new)) => {
+                let fields = changed_fields(old, new);
+                if fields.is_empty() {
+                    continue;
+                }
+                ("changed", fields)
+            }
+            (None, None) => continue,
+        };
+        differences.push(Difference { path: path.to_string(), change, fields });
+    }
+    differences
+}
+
+fn changed_fields(old: &SnapshotEntry, new: &SnapshotEntry) -> Vec<FieldChange> {
+    let mut fields = Vec::new();
+    let mut compare = |field, changed: bool, before: String, after: String| {
+        if changed {
+            fields.push(FieldChange { field, before, after });
+        }
+    };
+    compare("type", old.file_type != new.file_type, old.file_type.clone(), new.file_type.clone());
+    compare("mode", old.mode != new.mode, format!("{:04o}", old.mode), format!("{:04o}", new.mode));
+    // Owners are compared by id, so that renamed accounts don't count as changes.
+    let owner = |entry: &SnapshotEntry| format!("{}:{}", entry.user, entry.group);
+    compare("owner", (old.uid, old.gid) != (new.uid, new.gid), owner(old), owner(new));
+    compare("size", old.size != new.size, old.size.to_string(), new.size.to_string());
+    // Times are compared as instants, so that snapshots taken in different time zones match.
+    let parse = |mtime: &str| DateTime::parse_from_rfc3339(mtime);
+    let same_mtime = match (parse(&old.mtime), parse(&new.mtime)) {
+        (Ok(old_mtime), Ok(new_mtime)) => old_mtime == new_mtime,
+        _ => old.mtime == new.mtime,
+    };
+    compare("mtime", !same_mtime, old.mtime.clone(), new.mtime.clone());
+    let target = |entry: &SnapshotEntry| entry.symlink_target.clone().unwrap_or_default();
+    compare("target", old.symlink_target != new.symlink_target, target(old), target(new));
+    if let (Some(old_sha1), Some(new_sha1)) = (&old.sha1, &new.sha1) {
+        compare("hash", old_sha1 != new_sha1, old_sha1.clone(), new_sha1.clone());
+    }
+    fields
+}
+
+/// Writes `--diff` output in the chosen `--format`: one line per entry for the long format,
+/// `+`/`-`/`~` marking added, removed and changed entries.
+pub fn print_differences(differences: &[Difference], format: OutputFormat) {
+    match format {
+        OutputFormat::Long => {
+            for difference in differences {
+                let marker = match difference.change {
+                    "added" => '+',
+                    "removed" => '-',
+                    _ => '~',
+                };
+                let fields: Vec<String> = difference
+                    .fields
+                    .iter()
+                    .map(|f| format!("{} {} -> {}", f.field, f.before, f.after))
+                    .collect();
+                if fields.is_empty() {
+                    println!("{} {}", marker, difference.path);
+                } else {
+                    println!("{} {}  {}", marker, difference.path, fields.join(", "));
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let json = serde_json::to_string_pretty(differences).unwrap_or_default();
+            println!("{}", json);
+        }
+        OutputFormat::Ndjson => {
+            for difference in differences {
+                println!("{}", serde_json::to_string(difference).unwrap_or_default());
+            }
+        }
+        OutputFormat::Csv => {
+            // One row per changed field, or a single row with no field for added and removed.
+            println!("change,path,field,before,after");
+            for difference in differences {
+                let path = csv_field(&difference.path);
+                if difference.fields.is_empty() {
+                    println!("{},{},,,", difference.change, path);
+                }
+                for f in &difference.fields {
+                    let (before, after) = (csv_field(&f.before), csv_field(&f.after));
+                    println!("{},{},{},{},{}", difference.change, path, f.field, before, after);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str) -> SnapshotEntry {
+        SnapshotEntry {
+            path: path.to_string(),
+            file_type: "file".to_string(),
+            mode: 0o644,
+            uid: 1000,
+            user: "alice".to_string(),
+            gid: 1000,
+            group: "alice".to_string(),
+            size: 3,
+            mtime: "2024-05-01T12:00:00.000000000+00:00".to_string(),
+            symlink_target: None,
+            sha1: None,
+        }
+    }
+
+    fn fields(old: &SnapshotEntry, new: &SnapshotEntry) -> Vec<&'static str> {
+        changed_fields(old, new).iter().map(|f| f.field).collect()
+    }
+
+    #[test]
+    fn unchanged_entries() {
+        let mut new = entry("a");
+        // The same instant in another zone, and a renamed account.
+        new.mtime = "2024-05-01T14:00:00.000000000+02:00".to_string();
+        new.user = "alicia".to_string();
+        assert!(fields(&entry("a"), &new).is_empty());
+    }
+
+    #[test]
+    fn symlink_targets() {
+        let link = |target: &str| SnapshotEntry {
+            file_type: "symlink".to_string(),
+            symlink_target: Some(target.to_string()),
+            ..entry("l")
+        };
+        assert_eq!(fields(&link("a"), &link("b")), ["target"]);
+        let changes = changed_fields(&link("a"), &entry("l"));
+        let target = changes.iter().find(|f| f.field == "target").unwrap();
+        assert_eq!((target.before.as_str(), target.after.as_str()), ("a", ""));
+    }
+
+    #[test]
+    fn changed_fields_in_order() {
+        let mut new = entry("a");
+        new.mode = 0o600;
+        new.gid = 0;
+        new.size = 4;
+        new.mtime = "2024-05-01T12:00:01.000000000+00:00".to_string();
+        assert_eq!(fields(&entry("a"), &new), ["mode", "owner", "size", "mtime"]);
+        // Hashes count only when both sides have one.
+        new = entry("a");
+        new.sha1 = Some("00".to_string());
+        assert!(fields(&entry("a"), &new).is_empty());
+        let mut old = entry("a");
+        old.sha1 = Some("ff".to_string());
+        assert_eq!(fields(&old, &new), ["hash"]);
+    }
+
+    #[test]
+    fn dotfiles_without_all() {
+        let dir = std::env::temp_dir().join(format!("lslong-snapshot-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".config")).unwrap();
+        fs::write(dir.join(".config").join("rc"), "").unwrap();
+        fs::write(dir.join("visible"), "").unwrap();
+        let (snapshot, errors) = TreeSnapshot::take(&dir, &Options::default());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(errors.is_empty());
+        assert!(snapshot.hidden);
+        let paths: Vec<&str> = snapshot.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, [".", ".config", ".config/rc", "visible"]);
+
+        // Snapshots from before dotfiles were always recorded don't say so.
+        let mut json = serde_json::to_value(&snapshot).unwrap();
+        json.as_object_mut().unwrap().remove("hidden");
+        assert!(!serde_json::from_value::<TreeSnapshot>(json).unwrap().hidden);
+    }
+}
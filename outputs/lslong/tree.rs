@@ -0,0 +1,252 @@
+use std::path::Path;
+
+use super::entry::Entry;
+use super::list::{ListError, is_selected, list_dir, list_dir_reporting};
+use super::options::Options;
+
+/// The branch glyphs `--tree` draws before each name.
+struct TreeGlyphs {
+    branch: &'static str,
+    last: &'static str,
+    pipe: &'static str,
+    blank: &'static str,
+}
+
+const UNICODE_GLYPHS: TreeGlyphs =
+    TreeGlyphs { branch: "├── ", last: "└── ", pipe: "│   ", blank: "    " };
+const ASCII_GLYPHS: TreeGlyphs =
+    TreeGlyphs { branch: "|-- ", last: "`-- ", pipe: "|   ", blank: "    " };
+
+/// One line of `--tree` output.
+#[derive(Debug)]
+pub struct TreeNode {
+    pub entry: Entry,
+    /// The branch glyphs drawn before the name.
+    pub prefix: String,
+    /// The entry's size, or with `--du` everything beneath a directory as well.
+    pub size: u64,
+    /// Set on directories that would loop back to one of their ancestors.
+    pub note: Option<&'static str>,
+}
+
+/// Walks the directory `root` for `--tree`, returning a node for it and then for everything
+/// beneath it in display order, along with the directories that couldn't be listed.
+pub fn walk_tree(root: Entry, opts: &Options) -> (Vec<TreeNode>, Vec<ListError>) {
+    let mut ancestors = vec![(root.device, root.inode)];
+    let mut nodes = Vec::new();
+    let mut errors = Vec::new();
+    let beneath = walk(&root.path, "", 1, opts, &mut ancestors, &mut nodes, &mut errors);
+    let size = root.size + beneath;
+    nodes.insert(0, TreeNode { entry: root, prefix: String::new(), size, note: None });
+    (nodes, errors)
+}
+
+/// Appends the entries beneath `dir` to `nodes` in display order, descending into
+/// subdirectories until `--depth` is reached, and returns the bytes beneath `dir`.
+/// Symlinks are never followed, but bind mounts can still loop, so `ancestors` holds the
+/// (dev, inode) pairs of the directories above.
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    level: usize,
+    opts: &Options,
+    ancestors: &mut Vec<(u64, u64)>,
+    nodes: &mut Vec<TreeNode>,
+    errors: &mut Vec<ListError>,
+) -> u64 {
+    let glyphs = if opts.ascii { &ASCII_GLYPHS } else { &UNICODE_GLYPHS };
+    let mut entries = match list_dir_reporting(dir, opts, |e| errors.push(e)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(e);
+            return 0;
+        }
+    };
+    entries.retain(|entry| entry.name != "." && entry.name != "..");
+
+    // Directories are always drawn to keep the tree's shape; other entries must pass the
+    // filter, though `--du` totals still count them.
+    let mut total = 0;
+    entries.retain(|entry| {
+        let keep = entry.is_dir() || is_selected(entry, opts);
+        if !keep {
+            total += entry.size;
+        }
+        keep
+    });
+
+    let count = entries.len();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let node = nodes.len();
+        let (is_dir, id, size) = (entry.is_dir(), (entry.device, entry.inode), entry.size);
+        let path = entry.path.clone();
+        nodes.push(TreeNode {
+            entry,
+            prefix: format!("{}{}", prefix, if last { glyphs.last } else { glyphs.branch }),
+            size,
+            note: None,
+        });
+
+        let descend = opts.depth.is_none_or(|depth| level < depth);
+        if is_dir && (descend || opts.du) {
+            if ancestors.contains(&id) {
+                nodes[node].note = Some("[recursive, not followed]");
+            } else if descend {
+                ancestors.push(id);
+                let child_prefix = format!("{}{}", prefix, if last { glyphs.blank } else { glyphs.pipe });
+                nodes[node].size += walk(&path, &child_prefix, level + 1, opts, ancestors, nodes, errors);
+                ancestors.pop();
+            } else {
+                // Below `--depth` nothing is drawn, but `--du` totals still count it.
+                ancestors.push(id);
+                nodes[node].size += tree_size(&path, opts, ancestors);
+                ancestors.pop();
+            }
+        }
+        total += nodes[node].size;
+    }
+    total
+}
+
+/// The bytes beneath `dir`, for `--du` totals of directories deeper than `--depth`.
+fn tree_size(dir: &Path, opts: &Options, ancestors: &mut Vec<(u64, u64)>) -> u64 {
+    let Ok(entries) = list_dir(dir, opts) else {
+        return 0;
+    };
+    let mut total = 0;
+    for entry in &entries {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        total += entry.size;
+        let id = (entry.device, entry.inode);
+        if entry.is_dir() && !ancestors.contains(&id) {
+            ancestors.push(id);
+            total += tree_size(&entry.path, opts, ancestors);
+            ancestors.pop();
+        }
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+
+    use crate::lslong::parse_args;
+
+    /// A tree of files with known sizes, removed when it's dropped.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let root = std::env::temp_dir().join(format!("lslong-tree-{}-{}", std::process::id(), name));
+            fs::create_dir_all(root.join("dir").join("deep")).unwrap();
+            fs::write(root.join("a.txt"), [0; 10]).unwrap();
+            fs::write(root.join("dir").join("b.txt"), [0; 20]).unwrap();
+            fs::write(root.join("dir").join("deep").join("c.txt"), [0; 30]).unwrap();
+            fs::write(root.join("z.txt"), [0; 5]).unwrap();
+            Fixture(root)
+        }
+
+        fn size(&self, path: &str) -> u64 {
+            fs::symlink_metadata(self.0.join(path)).unwrap().len()
+        }
+
+        fn root(&self) -> Entry {
+            Entry::from_metadata("root", self.0.clone(), &fs::symlink_metadata(&self.0).unwrap())
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A node as its line of the tree, its size and its note.
+    type Line = (String, u64, Option<&'static str>);
+
+    fn walk(root: Entry, args: &[&str]) -> Vec<Line> {
+        let (opts, _) = parse_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        let (nodes, errors) = walk_tree(root, &opts);
+        assert!(errors.is_empty(), "{:?}", errors);
+        nodes.into_iter().map(|node| (node.prefix + &node.entry.name, node.size, node.note)).collect()
+    }
+
+    fn lines(nodes: &[Line]) -> Vec<&str> {
+        nodes.iter().map(|(line, _, _)| line.as_str()).collect()
+    }
+
+    fn sizes(nodes: &[Line]) -> Vec<u64> {
+        nodes.iter().map(|&(_, size, _)| size).collect()
+    }
+
+    #[test]
+    fn glyphs() {
+        let fixture = Fixture::new("glyphs");
+        let unicode = [
+            "root",
+            "├── a.txt",
+            "├── dir",
+            "│   ├── b.txt",
+            "│   └── deep",
+            "│       └── c.txt",
+            "└── z.txt",
+        ];
+        assert_eq!(lines(&walk(fixture.root(), &[])), unicode);
+        let ascii = [
+            "root",
+            "|-- a.txt",
+            "|-- dir",
+            "|   |-- b.txt",
+            "|   `-- deep",
+            "|       `-- c.txt",
+            "`-- z.txt",
+        ];
+        assert_eq!(lines(&walk(fixture.root(), &["--charset=ascii"])), ascii);
+    }
+
+    #[test]
+    fn depth() {
+        let fixture = Fixture::new("depth");
+        let one = walk(fixture.root(), &["--depth=1"]);
+        assert_eq!(lines(&one), ["root", "├── a.txt", "├── dir", "└── z.txt"]);
+        let two = walk(fixture.root(), &["--depth=2"]);
+        let expected = ["root", "├── a.txt", "├── dir", "│   ├── b.txt", "│   └── deep", "└── z.txt"];
+        assert_eq!(lines(&two), expected);
+    }
+
+    #[test]
+    fn du_totals() {
+        let fixture = Fixture::new("du");
+        let deep = fixture.size("dir/deep") + 30;
+        let dir = fixture.size("dir") + 20 + deep;
+        let root = fixture.size("") + 10 + dir + 5;
+        assert_eq!(sizes(&walk(fixture.root(), &["--du"])), [root, 10, dir, 20, deep, 30, 5]);
+        // Below `--depth`, and past the filter, sizes still count.
+        assert_eq!(sizes(&walk(fixture.root(), &["--du", "--depth=1"])), [root, 10, dir, 5]);
+        let filtered = walk(fixture.root(), &["--du", "--name", "[ab]*"]);
+        let expected = ["root", "├── a.txt", "└── dir", "    ├── b.txt", "    └── deep"];
+        assert_eq!(lines(&filtered), expected);
+        assert_eq!(sizes(&filtered), [root, 10, dir, 20, deep]);
+    }
+
+    #[test]
+    fn loops_are_noted() {
+        let fixture = Fixture::new("loop");
+        // A root that claims to be `dir/deep`, as if a bind mount led back to it.
+        let deep = fs::symlink_metadata(fixture.0.join("dir").join("deep")).unwrap();
+        let root = Entry { device: deep.dev(), inode: deep.ino(), ..fixture.root() };
+        let nodes = walk(root, &[]);
+        let expected = ["root", "├── a.txt", "├── dir", "│   ├── b.txt", "│   └── deep", "└── z.txt"];
+        assert_eq!(lines(&nodes), expected);
+        let notes: Vec<Option<&str>> = nodes.iter().map(|&(_, _, note)| note).collect();
+        assert_eq!(notes, [None, None, None, None, Some("[recursive, not followed]"), None]);
+    }
+}
+
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+
+use super::entry::Entry;
+use super::list::{ListError, list_dir};
+use super::options::Options;
+
+/// One change seen by `--watch`. `name` is the entry as it now is in the directory and `from`
+/// its previous name, so a move within the directory has both and moves in or out have one.
+#[derive(Debug)]
+pub struct Change {
+    pub event: &'static str,
+    pub name: Option<String>,
+    pub from: Option<String>,
+}
+
+impl Change {
+    fn new(event: &'static str, name: &str) -> Self {
+        Change { event, name: Some(name.to_string()), from: None }
+    }
+}
+
+/// How often `--poll` (or watching without inotify) rescans the directory.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const INOTIFY_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_DELETE_SELF
+    | libc::IN_MOVE_SELF;
+
+/// An inotify instance watching a single directory.
+pub struct Inotify {
+    file: fs::File,
+}
+
+impl Inotify {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let path = CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), INOTIFY_MASK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify { file })
+    }
+
+    /// Blocks for the next batch of events, as [`decode_events`] returns them.
+    pub fn next_changes(&mut self) -> io::Result<Option<Vec<Change>>> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let len = self.file.read(&mut buf)?;
+        Ok(decode_events(&buf[..len]))
+    }
+}
+
+/// Decodes a batch of inotify events, pairing `IN_MOVED_FROM`/`IN_MOVED_TO` by cookie.
+/// Returns `None` once the directory itself has been deleted or moved away.
+fn decode_events(buf: &[u8]) -> Option<Vec<Change>> {
+    const HEADER: usize = std::mem::size_of::<libc::inotify_event>();
+    let mut changes = Vec::new();
+    let mut moved_from: Vec<(u32, usize)> = Vec::new();
+    let mut gone = false;
+    let mut offset = 0;
+    while offset + HEADER <= buf.len() {
+        let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(buf[offset..].as_ptr().cast()) };
+        let name_bytes = buf.get(offset + HEADER..offset + HEADER + event.len as usize).unwrap_or_default();
+        // The name is NUL-padded to an alignment boundary.
+        let name = name_bytes.split(|&b| b == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += HEADER + event.len as usize;
+
+        let mask = event.mask;
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            changes.push(Change { event: "overflow", name: None, from: None });
+        } else if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_IGNORED) != 0 {
+            gone = true;
+        } else if mask & libc::IN_CREATE != 0 {
+            changes.push(Change::new("created", &name));
+        } else if mask & libc::IN_DELETE != 0 {
+            changes.push(Change::new("deleted", &name));
+        } else if mask & libc::IN_MODIFY != 0 {
+            changes.push(Change::new("modified", &name));
+        } else if mask & libc::IN_ATTRIB != 0 {
+            changes.push(Change::new("attrib", &name));
+        } else if mask & libc::IN_MOVED_FROM != 0 {
+            // Moved out of the directory until a matching IN_MOVED_TO says otherwise.
+            moved_from.push((event.cookie, changes.len()));
+            changes.push(Change { event: "moved", name: None, from: Some(name) });
+        } else if mask & libc::IN_MOVED_TO != 0 {
+            match moved_from.iter().position(|&(cookie, _)| cookie == event.cookie) {
+                Some(i) => changes[moved_from.swap_remove(i).1].name = Some(name),
+                None => changes.push(Change { event: "moved", name: Some(name), from: None }),
+            }
+        }
+    }
+    if gone && changes.is_empty() { None } else { Some(changes) }
+}
+
+/// Takes a `--poll` snapshot of `dir`: each shown entry, in listing order. Entries that can't
+/// be stat'd are left out; the redrawn listing reports them.
+pub fn snapshot(dir: &Path, opts: &Options) -> Result<Vec<Entry>, ListError> {
+    let mut entries = list_dir(dir, opts)?;
+    entries.retain(|entry| entry.name != "." && entry.name != "..");
+    Ok(entries)
+}
+
+/// Diffs two `--poll` snapshots. A name that vanished while another appeared with the same
+/// (dev, inode) was moved; otherwise size or mtime changes count as modifications and any
+/// other status change as an attribute change.
+pub fn diff_snapshots(before: &[Entry], after: &[Entry]) -> Vec<Change> {
+    let old: HashMap<&str, &Entry> = before.iter().map(|entry| (entry.name.as_str(), entry)).collect();
+    let new: HashMap<&str, &Entry> = after.iter().map(|entry| (entry.name.as_str(), entry)).collect();
+    let same_file = |a: &Entry, b: &Entry| (a.device, a.inode) == (b.device, b.inode);
+
+    let mut changes = Vec::new();
+    let mut moved_to = Vec::new();
+    for entry in before {
+        let name = &entry.name;
+        if new.contains_key(name.as_str()) {
+            continue;
+        }
+        let appeared = |to: &Entry| !old.contains_key(to.name.as_str()) && same_file(entry, to);
+        match after.iter().find(|to| appeared(to)) {
+            Some(to) => {
+                moved_to.push(to.name.as_str());
+                changes.push(Change { event: "moved", name: Some(to.name.clone()), from: Some(name.clone()) });
+            }
+            None => changes.push(Change::new("deleted", name)),
+        }
+    }
+    for entry in after {
+        let name = &entry.name;
+        let Some(was) = old.get(name.as_str()) else {
+            if !moved_to.contains(&name.as_str()) {
+                changes.push(Change::new("created", name));
+            }
+            continue;
+        };
+        let contents = |e: &Entry| (e.device, e.inode, e.size, e.modified);
+        let attributes = |e: &Entry| (e.file_type, e.permissions, e.uid, e.gid, e.nlink, e.changed);
+        if contents(was) != contents(entry) {
+            changes.push(Change::new("modified", name));
+        } else if attributes(was) != attributes(entry) {
+            changes.push(Change::new("attrib", name));
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration as TimeDelta;
+
+    use crate::lslong::entry::Permissions;
+
+    fn shape(changes: &[Change]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        changes.iter().map(|c| (c.event, c.name.as_deref(), c.from.as_deref())).collect()
+    }
+
+    /// A regular file entry named `name`, with inode `inode`.
+    fn entry(name: &str, inode: u64) -> Entry {
+        let info = fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let mut entry = Entry::from_metadata(name, name.into(), &info);
+        entry.inode = inode;
+        entry
+    }
+
+    #[test]
+    fn unchanged() {
+        let listing = [entry("a", 1), entry("b", 2)];
+        assert!(diff_snapshots(&listing, &listing.clone()).is_empty());
+    }
+
+    #[test]
+    fn created_and_deleted() {
+        let changes = diff_snapshots(&[entry("a", 1), entry("b", 2)], &[entry("b", 2), entry("c", 3)]);
+        assert_eq!(shape(&changes), [("deleted", Some("a"), None), ("created", Some("c"), None)]);
+    }
+
+    #[test]
+    fn moved() {
+        let changes = diff_snapshots(&[entry("a", 1), entry("b", 2)], &[entry("b", 2), entry("z", 1)]);
+        assert_eq!(shape(&changes), [("moved", Some("z"), Some("a"))]);
+        // Replacing a file with a new one under the same name is a modification.
+        let changes = diff_snapshots(&[entry("a", 1)], &[entry("a", 9)]);
+        assert_eq!(shape(&changes), [("modified", Some("a"), None)]);
+    }
+
+    #[test]
+    fn modified_and_attributes() {
+        let names = ["size", "mtime", "mode", "owner", "ctime"];
+        let before: Vec<Entry> = names.iter().zip(1..).map(|(name, inode)| entry(name, inode)).collect();
+        let mut after = before.clone();
+        after[0].size += 1;
+        after[1].modified += TimeDelta::seconds(1);
+        after[2].permissions = Permissions::from_mode(0o600);
+        after[3].uid += 1;
+        after[4].changed += TimeDelta::nanoseconds(1);
+        // Contents changes win over attribute changes to the same entry.
+        after[1].nlink += 1;
+        let changes = diff_snapshots(&before, &after);
+        assert_eq!(
+            shape(&changes),
+            [
+                ("modified", Some("size"), None),
+                ("modified", Some("mtime"), None),
+                ("attrib", Some("mode"), None),
+                ("attrib", Some("owner"), None),
+                ("attrib", Some("ctime"), None),
+            ]
+        );
+    }
+
+    /// The bytes inotify reads as one event, with the name NUL-padded to 16 bytes.
+    fn event(mask: u32, cookie: u32, name: &str) -> Vec<u8> {
+        let len = if name.is_empty() { 0 } else { (name.len() / 16 + 1) * 16 };
+        let mut bytes = Vec::new();
+        for field in [1, mask as i32, cookie as i32, len as i32] {
+            bytes.extend_from_slice(&field.to_ne_bytes());
+        }
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.resize(16 + len, 0);
+        bytes
+    }
+
+    #[test]
+    fn inotify_events() {
+        let events = [
+            event(libc::IN_CREATE, 0, "new"),
+            event(libc::IN_DELETE | libc::IN_ISDIR, 0, "a-rather-long-directory-name"),
+            event(libc::IN_MODIFY, 0, "log"),
+            event(libc::IN_ATTRIB, 0, "script"),
+            event(libc::IN_MOVED_FROM, 7, "old"),
+            event(libc::IN_MOVED_FROM, 8, "leaving"),
+            event(libc::IN_MOVED_TO, 9, "arriving"),
+            event(libc::IN_MOVED_TO, 7, "renamed"),
+            event(libc::IN_Q_OVERFLOW, 0, ""),
+        ];
+        let changes = decode_events(&events.concat()).unwrap();
+        assert_eq!(
+            shape(&changes),
+            [
+                ("created", Some("new"), None),
+                ("deleted", Some("a-rather-long-directory-name"), None),
+                ("modified", Some("log"), None),
+                ("attrib", Some("script"), None),
+                ("moved", Some("renamed"), Some("old")),
+                ("moved", None, Some("leaving")),
+                ("moved", Some("arriving"), None),
+                ("overflow", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn inotify_directory_gone() {
+        assert!(decode_events(&event(libc::IN_DELETE_SELF, 0, "")).is_none());
+        let events = [event(libc::IN_MOVE_SELF, 0, ""), event(libc::IN_IGNORED, 0, "")].concat();
+        assert!(decode_events(&events).is_none());
+        // Changes that came before still get reported.
+        let events = [event(libc::IN_CREATE, 0, "last"), event(libc::IN_DELETE_SELF, 0, "")].concat();
+        assert_eq!(shape(&decode_events(&events).unwrap()), [("created", Some("last"), None)]);
+        assert!(decode_events(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+pub(crate) const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub(crate) const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+pub(crate) const SELINUX_XATTR: &str = "security.selinux";
+
+/// The names of `path`'s extended attributes, without following symlinks. Filesystems
+/// without xattr support simply have none. Every long-format row asks, so the usual short
+/// list takes a single call.
+pub(crate) fn list_xattrs(path: &Path) -> Vec<String> {
+    let Ok(cpath) = CString::new(path.as_os_str().as_bytes()) else {
+        return Vec::new();
+    };
+    let mut buf = vec![0u8; 1024];
+    loop {
+        let len = unsafe { libc::llistxattr(cpath.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len >= 0 {
+            buf.truncate(len as usize);
+            break;
+        }
+        if std::io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return Vec::new();
+        }
+        // Too long for the buffer: ask for its size, and try again in case it grows meanwhile.
+        let size = unsafe { libc::llistxattr(cpath.as_ptr(), std::ptr::null_mut(), 0) };
+        if size <= 0 {
+            return Vec::new();
+        }
+        buf.resize(size as usize, 0);
+    }
+    buf.split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect()
+}
+
+/// The value of the extended attribute `name` on `path` itself, not a symlink's target.
+pub(crate) fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let cpath = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let cname = CString::new(name).ok()?;
+    loop {
+        let size = unsafe { libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return None;
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = unsafe { libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return None;
+        }
+        buf.truncate(len as usize);
+        return Some(buf);
+    }
+}
+
+/// Formats an xattr value as getfattr does: quoted text, or hex when it isn't printable.
+pub(crate) fn xattr_value_string(value: &[u8]) -> String {
+    let text = value.strip_suffix(&[0]).unwrap_or(value);
+    match std::str::from_utf8(text) {
+        Ok(text) if !text.chars().any(char::is_control) => format!("\"{}\"", text),
+        _ => value.iter().fold(String::from("0x"), |hex, b| hex + &format!("{:02x}", b)),
+    }
+}
+
+/// The character GNU ls appends to the mode: `+` for a POSIX ACL, `.` for an SELinux
+/// context, nothing otherwise.
+pub(crate) fn xattr_indicator(names: &[String]) -> &'static str {
+    let has = |wanted: &str| names.iter().any(|name| name == wanted);
+    if has(ACL_ACCESS_XATTR) || has(ACL_DEFAULT_XATTR) {
+        "+"
+    } else if has(SELINUX_XATTR) {
+        "."
+    } else {
+        ""
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn indicators() {
+        let cases: [(&[&str], &str); 7] = [
+            (&[], ""),
+            // GNU ls has no mark for other attributes, unlike macOS's `@`.
+            (&["user.comment"], ""),
+            (&[SELINUX_XATTR], "."),
+            (&[ACL_ACCESS_XATTR], "+"),
+            (&[ACL_DEFAULT_XATTR], "+"),
+            (&[SELINUX_XATTR, ACL_ACCESS_XATTR], "+"),
+            (&["user.comment", SELINUX_XATTR], "."),
+        ];
+        for (list, expected) in cases {
+            assert_eq!(xattr_indicator(&names(list)), expected, "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn values() {
+        let cases: [(&[u8], &str); 8] = [
+            (b"text", "\"text\""),
+            (b"system_u:object_r:tmp_t:s0\0", "\"system_u:object_r:tmp_t:s0\""),
+            (b"", "\"\""),
+            ("caf\u{e9}".as_bytes(), "\"caf\u{e9}\""),
+            (b"\xff\xfe", "0xfffe"),
+            (b"two\nlines", "0x74776f0a6c696e6573"),
+            (b"\x01\x02\0", "0x010200"),
+            (b"\0\0", "0x0000"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(xattr_value_string(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn listing() {
+        let path = std::env::temp_dir().join(format!("lslong-xattr-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        assert!(list_xattrs(&path).is_empty());
+        // More names than fit in the first buffer.
+        let mut expected: Vec<String> =
+            (0..40).map(|i| format!("user.lslong-test-{:03}-{}", i, "x".repeat(40))).collect();
+        let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+        for name in &expected {
+            let cname = CString::new(name.as_str()).unwrap();
+            let set = unsafe { libc::lsetxattr(cpath.as_ptr(), cname.as_ptr(), b"v".as_ptr().cast(), 1, 0) };
+            if set != 0 {
+                // Not every filesystem takes user attributes.
+                eprintln!("skipping: {}", std::io::Error::last_os_error());
+                fs::remove_file(&path).unwrap();
+                return;
+            }
+        }
+        let mut listed = list_xattrs(&path);
+        listed.retain(|name| name.starts_with("user."));
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(get_xattr(&path, &expected[0]).as_deref(), Some(&b"v"[..]));
+        assert_eq!(get_xattr(&path, "user.missing"), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
@@ -1,98 +1,2665 @@
-use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::rc::Rc;
+use std::str::Chars;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use std::process::{Command, exit};
-use std::ptr;
-use std::os::unix::process::CommandExt;
-use std::ffi::{CString, CStr};
 
-fn expect<T>(ptr: Option<T>, msg: &str) {
-    if ptr.is_none() {
-        eprintln!("{}", msg);
-        exit(1);
-    }
-}
-
-fn buflen(s: &str) -> usize {
-    s.len() + 1
-}
+use serde::{Deserialize, Serialize};
 
 fn prompt() {
     print!("mysh% ");
     io::stdout().flush().unwrap();
 }
 
-fn get_next_command(last_command: Option<&str>) -> Option<String> {
-    let mut cmd_buf = String::new();
-    prompt();
+/// The result of reading one line of input from the user.
+enum Input {
+    Line(String),
+    Skip,
+    Eof,
+}
 
-    if io::stdin().read_line(&mut cmd_buf).is_err() {
-        return None;
+/// Reads one line from stdin without its trailing newline, or `None` at end of input.
+fn read_line() -> Option<String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
     }
+}
+
+fn get_next_command(last_command: Option<&str>) -> Input {
+    prompt();
 
-    let cmd_buf = cmd_buf.trim_end();
+    let mut cmd_buf = match read_line() {
+        Some(line) => line,
+        None => return Input::Eof,
+    };
 
     if cmd_buf == "!!" {
         if let Some(last_cmd) = last_command {
             println!("mysh% {}", last_cmd);
-            return Some(last_cmd.to_string());
+            return Input::Line(last_cmd.to_string());
         } else {
             eprintln!("No commands in history.");
-            return None;
+            return Input::Skip;
+        }
+    }
+
+    // Keep reading while a quote or here-document is still open.
+    while let Err(ParseError::Incomplete(_)) = parse(&cmd_buf) {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        match read_line() {
+            Some(line) => {
+                cmd_buf.push('\n');
+                cmd_buf.push_str(&line);
+            }
+            None => break,
+        }
+    }
+
+    Input::Line(cmd_buf)
+}
+
+#[derive(Debug)]
+enum ParseError {
+    /// The input ended inside a quote or here-document; more lines may complete it.
+    Incomplete(String),
+    Syntax(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Incomplete(msg) | ParseError::Syntax(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// A lexical token. Words keep their quotes; they are removed during expansion.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Op(&'static str),
+    HereDoc(HereDoc),
+}
+
+/// The body of a `<<` here-document, read from the lines following the command.
+#[derive(Debug, Clone, PartialEq)]
+struct HereDoc {
+    body: String,
+    expand: bool,
+}
+
+const OPERATORS: [&str; 15] = [
+    "<<<", "<<-", "&&", "||", "<<", ">>", ";;", ";", "&", "|", "(", ")", "<", ">", "\n",
+];
+
+fn lex_word(chars: &mut Peekable<Chars>) -> Result<String, ParseError> {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || "&|;()<>".contains(c) {
+            break;
+        }
+        chars.next();
+        word.push(c);
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+            }
+            '\'' | '"' => {
+                let mut closed = false;
+                while let Some(next) = chars.next() {
+                    word.push(next);
+                    if next == '\\' && c == '"' {
+                        if let Some(escaped) = chars.next() {
+                            word.push(escaped);
+                        }
+                    } else if next == c {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    return Err(ParseError::Incomplete(format!(
+                        "unexpected EOF while looking for matching `{}'",
+                        c
+                    )));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(word)
+}
+
+/// Strips quotes and backslashes from a here-document delimiter.
+fn remove_quotes(raw: &str) -> String {
+    let mut out = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            '\'' | '"' => {}
+            _ => out.push(c),
         }
     }
+    out
+}
 
-    Some(cmd_buf.to_string())
+/// A here-document whose body hasn't been read yet.
+struct PendingHereDoc {
+    token: usize,
+    delimiter: String,
+    strip_tabs: bool,
 }
 
-fn get_n_spaces(s: &str) -> usize {
-    s.chars().filter(|&c| c == ' ').count()
+/// Reads the bodies of pending here-documents from the lines after a newline.
+fn read_heredocs(
+    chars: &mut Peekable<Chars>,
+    tokens: &mut [Token],
+    pending: &mut Vec<PendingHereDoc>,
+) -> Result<(), ParseError> {
+    for doc in pending.drain(..) {
+        let mut body = String::new();
+        loop {
+            if chars.peek().is_none() {
+                return Err(ParseError::Incomplete(format!(
+                    "here-document delimited by `{}' is not terminated",
+                    doc.delimiter
+                )));
+            }
+            let line: String = chars.by_ref().take_while(|&c| c != '\n').collect();
+            let line = if doc.strip_tabs { line.trim_start_matches('\t') } else { &line };
+            if line == doc.delimiter {
+                break;
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        if let Token::HereDoc(heredoc) = &mut tokens[doc.token] {
+            heredoc.body = body;
+        }
+    }
+    Ok(())
 }
 
-fn tokenize(s: &str) -> Vec<String> {
+fn tokenize(s: &str) -> Result<Vec<Token>, ParseError> {
     let mut tokens = Vec::new();
-    let mut iter = s.split_whitespace().peekable();
+    let mut pending = Vec::new();
+    let mut chars = s.chars().peekable();
 
-    while let Some(token) = iter.next() {
-        if token.starts_with('"') && token.ends_with('"') && token.len() > 1 {
-            tokens.push(token[1..token.len()-1].to_string());
-        } else {
-            tokens.push(token.to_string());
+    while let Some(&c) = chars.peek() {
+        if c != '\n' && c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '#' {
+            while chars.peek().is_some_and(|&c| c != '\n') {
+                chars.next();
+            }
+            continue;
         }
+
+        let rest: String = chars.clone().take(3).collect();
+        if let Some(&op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            for _ in 0..op.len() {
+                chars.next();
+            }
+            match op {
+                "<<" | "<<-" => {
+                    while chars.peek().is_some_and(|&c| c == ' ' || c == '\t') {
+                        chars.next();
+                    }
+                    let raw = lex_word(&mut chars)?;
+                    if raw.is_empty() {
+                        return Err(ParseError::Syntax(
+                            "syntax error near unexpected token `newline'".to_string(),
+                        ));
+                    }
+                    pending.push(PendingHereDoc {
+                        token: tokens.len(),
+                        delimiter: remove_quotes(&raw),
+                        strip_tabs: op == "<<-",
+                    });
+                    tokens.push(Token::HereDoc(HereDoc {
+                        body: String::new(),
+                        expand: !raw.contains(['\'', '"', '\\']),
+                    }));
+                }
+                "\n" => {
+                    tokens.push(Token::Op(op));
+                    read_heredocs(&mut chars, &mut tokens, &mut pending)?;
+                }
+                _ => tokens.push(Token::Op(op)),
+            }
+            continue;
+        }
+
+        tokens.push(Token::Word(lex_word(&mut chars)?));
+    }
+
+    if let Some(doc) = pending.first() {
+        return Err(ParseError::Incomplete(format!(
+            "here-document delimited by `{}' is not terminated",
+            doc.delimiter
+        )));
     }
 
-    tokens
+    Ok(tokens)
+}
+
+/// A single command: a simple command, a subshell, or one of the compound commands.
+#[derive(Debug, Clone)]
+enum Node {
+    Simple { assigns: Vec<(String, String)>, words: Vec<String> },
+    Subshell(List),
+    Group(List),
+    Not(Box<Cmd>),
+    If { branches: Vec<(List, List)>, otherwise: Option<List> },
+    While { cond: List, body: List, until: bool },
+    For { var: String, words: Option<Vec<String>>, body: List },
+    Case { word: String, arms: Vec<CaseArm> },
+    Function { name: String, body: Rc<Cmd> },
 }
 
-fn execute(args: Vec<String>) {
-    let mut command = Command::new(&args[0]);
-    let args_slice: Vec<&str> = args.iter().skip(1).map(|s| s.as_str()).collect();
+#[derive(Debug, Clone)]
+struct CaseArm {
+    patterns: Vec<String>,
+    body: List,
+}
 
-    let do_wait = if args_slice.last() == Some(&"&") {
-        command.arg("&");
-        false 
-    } else {
-        true
-    };
+/// An input or output redirection attached to a command.
+#[derive(Debug, Clone)]
+enum Redirect {
+    Input(String),
+    Output(String),
+    Append(String),
+    HereDoc(HereDoc),
+    HereString(String),
+}
+
+/// A command together with the redirections that apply to it.
+#[derive(Debug, Clone)]
+struct Cmd {
+    node: Node,
+    redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Connector {
+    And,
+    Or,
+}
+
+/// How `time` reports the resources used by a pipeline.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeFormat {
+    Default,
+    Posix,
+    Json,
+}
+
+/// Commands joined by `|`, optionally prefixed by the `time` keyword.
+#[derive(Debug, Clone)]
+struct Pipeline {
+    cmds: Vec<Cmd>,
+    time: Option<TimeFormat>,
+}
+
+/// Pipelines joined by `&&` and `||`.
+#[derive(Debug, Clone)]
+struct AndOr {
+    first: Pipeline,
+    rest: Vec<(Connector, Pipeline)>,
+}
+
+#[derive(Debug, Clone)]
+struct ListItem {
+    and_or: AndOr,
+    background: bool,
+}
+
+type List = Vec<ListItem>;
+
+/// Reserved words that end a list, so they can't start a command.
+const TERMINATORS: [&str; 8] = ["then", "elif", "else", "fi", "do", "done", "esac", "}"];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Token::Op(o)) if *o == op)
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == word)
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        if !self.peek_word(word) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Consumes an optional `;` or newlines before a reserved word such as `do` or `in`.
+    fn skip_separator(&mut self) {
+        if self.peek_op(";") {
+            self.pos += 1;
+        }
+        self.skip_newlines();
+    }
+
+    fn peek_redirect(&self) -> bool {
+        ["<", ">", ">>", "<<<"].iter().any(|op| self.peek_op(op))
+            || matches!(self.peek(), Some(Token::HereDoc(_)))
+    }
+
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(Token::Op("\n")) => {
+                ParseError::Syntax("syntax error near unexpected token `newline'".to_string())
+            }
+            Some(Token::Op(op)) => {
+                ParseError::Syntax(format!("syntax error near unexpected token `{}'", op))
+            }
+            Some(Token::Word(w)) => {
+                ParseError::Syntax(format!("syntax error near unexpected token `{}'", w))
+            }
+            Some(Token::HereDoc(_)) => {
+                ParseError::Syntax("syntax error near unexpected token `<<'".to_string())
+            }
+            None => ParseError::Incomplete("syntax error: unexpected end of file".to_string()),
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek_op("\n") {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_program(&mut self) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        if self.peek().is_some() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn parse_list(&mut self) -> Result<List, ParseError> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_newlines();
+            let starts_command = match self.peek() {
+                Some(Token::Word(word)) => !TERMINATORS.contains(&word.as_str()),
+                _ => self.peek_op("(") || self.peek_redirect(),
+            };
+            if !starts_command {
+                break;
+            }
+            let and_or = self.parse_and_or()?;
+            let background = self.peek_op("&");
+            let separated = background || self.peek_op(";") || self.peek_op("\n");
+            list.push(ListItem { and_or, background });
+            if !separated {
+                break;
+            }
+            self.pos += 1;
+        }
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = if self.peek_op("&&") {
+                Connector::And
+            } else if self.peek_op("||") {
+                Connector::Or
+            } else {
+                break;
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((connector, self.parse_pipeline()?));
+        }
+        Ok(AndOr { first, rest })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let mut time = None;
+        if self.peek_word("time") {
+            self.pos += 1;
+            let mut format = TimeFormat::Default;
+            loop {
+                if self.peek_word("-p") {
+                    format = TimeFormat::Posix;
+                } else if self.peek_word("--json") {
+                    format = TimeFormat::Json;
+                } else {
+                    break;
+                }
+                self.pos += 1;
+            }
+            time = Some(format);
+        }
+
+        let mut cmds = vec![self.parse_command()?];
+        while self.peek_op("|") {
+            self.pos += 1;
+            self.skip_newlines();
+            cmds.push(self.parse_command()?);
+        }
+        Ok(Pipeline { cmds, time })
+    }
+
+    /// Parses a redirection at the current position, if there is one.
+    fn parse_redirect(&mut self) -> Result<Option<Redirect>, ParseError> {
+        if let Some(Token::HereDoc(doc)) = self.peek() {
+            let doc = doc.clone();
+            self.pos += 1;
+            return Ok(Some(Redirect::HereDoc(doc)));
+        }
+        let op = match self.peek() {
+            Some(Token::Op(op @ ("<" | ">" | ">>" | "<<<"))) => *op,
+            _ => return Ok(None),
+        };
+        self.pos += 1;
+        let target = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        Ok(Some(match op {
+            "<" => Redirect::Input(target),
+            ">" => Redirect::Output(target),
+            ">>" => Redirect::Append(target),
+            _ => Redirect::HereString(target),
+        }))
+    }
+
+    /// Parses a list that must be followed by one of `ends`, which is left unconsumed.
+    fn parse_body(&mut self, ends: &[&str]) -> Result<List, ParseError> {
+        let list = self.parse_list()?;
+        if !ends.iter().any(|end| self.peek_word(end)) {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn parse_command(&mut self) -> Result<Cmd, ParseError> {
+        let node = match self.peek().cloned() {
+            Some(Token::Op("(")) => {
+                self.pos += 1;
+                let list = self.parse_list()?;
+                if list.is_empty() || !self.peek_op(")") {
+                    return Err(self.unexpected());
+                }
+                self.pos += 1;
+                Node::Subshell(list)
+            }
+            Some(Token::Word(word)) => match word.as_str() {
+                "!" => {
+                    self.pos += 1;
+                    return Ok(Cmd { node: Node::Not(Box::new(self.parse_command()?)), redirects: Vec::new() });
+                }
+                "{" => {
+                    self.pos += 1;
+                    let list = self.parse_body(&["}"])?;
+                    self.pos += 1;
+                    Node::Group(list)
+                }
+                "if" => self.parse_if()?,
+                "while" | "until" => {
+                    let until = word == "until";
+                    self.pos += 1;
+                    let cond = self.parse_body(&["do"])?;
+                    let body = self.parse_do_group()?;
+                    Node::While { cond, body, until }
+                }
+                "for" => self.parse_for()?,
+                "case" => self.parse_case()?,
+                _ if is_name(&word) && self.tokens.get(self.pos + 1) == Some(&Token::Op("(")) => {
+                    let name = word;
+                    self.pos += 2;
+                    if !self.peek_op(")") {
+                        return Err(self.unexpected());
+                    }
+                    self.pos += 1;
+                    self.skip_newlines();
+                    Node::Function { name, body: Rc::new(self.parse_command()?) }
+                }
+                _ => return self.parse_simple(),
+            },
+            _ => return self.parse_simple(),
+        };
+
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.parse_redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(Cmd { node, redirects })
+    }
+
+    fn parse_do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_word("do")?;
+        let body = self.parse_body(&["done"])?;
+        self.pos += 1;
+        Ok(body)
+    }
+
+    fn parse_if(&mut self) -> Result<Node, ParseError> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.pos += 1;
+        loop {
+            let cond = self.parse_body(&["then"])?;
+            self.pos += 1;
+            let body = self.parse_body(&["elif", "else", "fi"])?;
+            branches.push((cond, body));
+            if self.peek_word("elif") {
+                self.pos += 1;
+                continue;
+            }
+            if self.peek_word("else") {
+                self.pos += 1;
+                otherwise = Some(self.parse_body(&["fi"])?);
+            }
+            self.pos += 1;
+            break;
+        }
+        Ok(Node::If { branches, otherwise })
+    }
+
+    fn parse_for(&mut self) -> Result<Node, ParseError> {
+        self.pos += 1;
+        let var = match self.peek() {
+            Some(Token::Word(word)) if is_name(word) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+
+        let mut words = None;
+        if self.peek_word("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(word)) = self.peek() {
+                list.push(word.clone());
+                self.pos += 1;
+            }
+            words = Some(list);
+        }
+        self.skip_separator();
+        let body = self.parse_do_group()?;
+        Ok(Node::For { var, words, body })
+    }
+
+    fn parse_case(&mut self) -> Result<Node, ParseError> {
+        self.pos += 1;
+        let word = match self.peek() {
+            Some(Token::Word(word)) => word.clone(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        self.skip_newlines();
+        self.expect_word("in")?;
+
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek_word("esac") {
+                self.pos += 1;
+                break;
+            }
+            if self.peek_op("(") {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Token::Word(pattern)) => patterns.push(pattern.clone()),
+                    _ => return Err(self.unexpected()),
+                }
+                self.pos += 1;
+                if !self.peek_op("|") {
+                    break;
+                }
+                self.pos += 1;
+            }
+            if !self.peek_op(")") {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+            let body = self.parse_list()?;
+            arms.push(CaseArm { patterns, body });
+            if self.peek_op(";;") {
+                self.pos += 1;
+            } else if !self.peek_word("esac") {
+                return Err(self.unexpected());
+            }
+        }
+        Ok(Node::Case { word, arms })
+    }
+
+    fn parse_simple(&mut self) -> Result<Cmd, ParseError> {
+        let mut redirects = Vec::new();
+        let mut assigns = Vec::new();
+        let mut words = Vec::new();
+        loop {
+            if let Some(redirect) = self.parse_redirect()? {
+                redirects.push(redirect);
+                continue;
+            }
+            let Some(Token::Word(word)) = self.peek() else {
+                break;
+            };
+            match split_assignment(word) {
+                Some((name, value)) if words.is_empty() => assigns.push((name, value)),
+                _ => words.push(word.clone()),
+            }
+            self.pos += 1;
+        }
+        if assigns.is_empty() && words.is_empty() && redirects.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(Cmd { node: Node::Simple { assigns, words }, redirects })
+    }
+}
 
-    let child = command.spawn().expect("Failed to start command");
+/// Tokenizes and parses a complete command line.
+fn parse(s: &str) -> Result<List, ParseError> {
+    Parser::new(tokenize(s)?).parse_program()
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
 
-    if do_wait {
-        let _ = child.wait().expect("Command wasn't running");
+/// Splits a `NAME=value` word into its name and (unexpanded) value.
+fn split_assignment(word: &str) -> Option<(String, String)> {
+    let (name, value) = word.split_once('=')?;
+    if is_name(name) {
+        Some((name.to_string(), value.to_string()))
+    } else {
+        None
     }
 }
 
-fn main() {
-    let mut last_command: Option<String> = None;
+/// A pending `break`, `continue` or `return` that unwinds the lists enclosing it.
+#[derive(Debug, Clone, Copy)]
+enum Flow {
+    Break(u32),
+    Continue(u32),
+    Return(i32),
+}
+
+/// A piece of an expanded word, remembering where its text came from.
+enum Part {
+    /// Unquoted text written in the word itself.
+    Literal(String),
+    /// Quoted or escaped text, exempt from field splitting and globbing.
+    Quoted(String),
+    /// The result of an unquoted expansion, subject to field splitting and globbing.
+    Expanded(String),
+    /// The boundary between two elements of `"$@"`.
+    FieldBreak,
+}
+
+/// Shell state. A subshell gets its own copy, so changes made there never reach the parent.
+#[derive(Clone)]
+struct Shell {
+    vars: HashMap<String, String>,
+    exported: HashSet<String>,
+    functions: HashMap<String, Rc<Cmd>>,
+    script_name: String,
+    positional: Vec<String>,
+    last_status: i32,
+    exit_requested: Option<i32>,
+    flow: Option<Flow>,
+    loop_depth: u32,
+    jobs: Vec<libc::pid_t>,
+    usage: Option<Usage>,
+    argv_log: Option<Rc<ArgvLog>>,
+}
+
+impl Shell {
+    fn new(script_name: String, positional: Vec<String>) -> Self {
+        Shell {
+            vars: std::env::vars().collect(),
+            exported: std::env::vars().map(|(name, _)| name).collect(),
+            functions: HashMap::new(),
+            script_name,
+            positional,
+            last_status: 0,
+            exit_requested: None,
+            flow: None,
+            loop_depth: 0,
+            jobs: Vec::new(),
+            usage: None,
+            argv_log: None,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> String {
+        match name {
+            "?" => self.last_status.to_string(),
+            "$" => std::process::id().to_string(),
+            "#" => self.positional.len().to_string(),
+            "@" | "*" => self.positional.join(" "),
+            "0" => self.script_name.clone(),
+            _ if name.bytes().all(|b| b.is_ascii_digit()) => name
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.positional.get(n.wrapping_sub(1)))
+                .cloned()
+                .unwrap_or_default(),
+            _ => self.vars.get(name).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Performs parameter expansion and quote removal on a raw word, without field splitting.
+    fn expand_word(&self, raw: &str) -> String {
+        join_parts(self.expand(raw, false))
+    }
+
+    /// Expands an unquoted here-document body, where quotes have no special meaning.
+    fn expand_heredoc(&self, body: &str) -> String {
+        join_parts(self.expand(body, true))
+    }
+
+    /// Expands a `case` pattern, keeping quoted characters literal.
+    fn expand_pattern(&self, raw: &str) -> String {
+        let mut pattern = String::new();
+        for part in self.expand(raw, false) {
+            match part {
+                Part::Literal(text) | Part::Expanded(text) => pattern.push_str(&text),
+                Part::Quoted(text) => pattern.push_str(&escape_glob(&text)),
+                Part::FieldBreak => pattern.push(' '),
+            }
+        }
+        pattern
+    }
+
+    /// Expands command words into fields: parameter expansion, field splitting, then globbing.
+    fn expand_fields(&self, words: &[String]) -> Vec<String> {
+        let mut fields = Vec::new();
+        for raw in words {
+            let mut field = Field::default();
+            for part in self.expand(raw, false) {
+                match part {
+                    Part::Literal(text) => field.push(&text, &text),
+                    Part::Quoted(text) => field.push(&text, &escape_glob(&text)),
+                    Part::Expanded(text) => {
+                        if text.starts_with(char::is_whitespace) {
+                            field.finish(&mut fields);
+                        }
+                        for (i, piece) in text.split_whitespace().enumerate() {
+                            if i > 0 {
+                                field.finish(&mut fields);
+                            }
+                            field.push(piece, piece);
+                        }
+                        if text.ends_with(char::is_whitespace) {
+                            field.finish(&mut fields);
+                        }
+                    }
+                    Part::FieldBreak => {
+                        field.started = true;
+                        field.finish(&mut fields);
+                    }
+                }
+            }
+            field.finish(&mut fields);
+        }
+        fields
+    }
+
+    fn expand(&self, raw: &str, heredoc: bool) -> Vec<Part> {
+        let mut parts = Vec::new();
+        let mut chars = raw.chars().peekable();
+        let mut in_double = heredoc;
+        let mut quote_start = 0;
+        let mut saw_at = false;
+
+        let text = |in_double: bool, s: String| if in_double { Part::Quoted(s) } else { Part::Literal(s) };
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' if !in_double => {
+                    let quoted: String = chars.by_ref().take_while(|&c| c != '\'').collect();
+                    parts.push(Part::Quoted(quoted));
+                }
+                '"' if !heredoc => {
+                    in_double = !in_double;
+                    if in_double {
+                        quote_start = parts.len();
+                        saw_at = false;
+                    } else if parts.len() == quote_start && !saw_at {
+                        parts.push(Part::Quoted(String::new()));
+                    }
+                }
+                '\\' => match chars.next() {
+                    Some(next) if !in_double || "$`\"\\".contains(next) && !(heredoc && next == '"') => {
+                        parts.push(Part::Quoted(next.to_string()))
+                    }
+                    Some(next) => parts.push(Part::Quoted(format!("\\{}", next))),
+                    None => parts.push(text(in_double, "\\".to_string())),
+                },
+                '$' => {
+                    let name = match chars.peek().copied() {
+                        Some('{') => {
+                            chars.next();
+                            chars.by_ref().take_while(|&c| c != '}').collect()
+                        }
+                        Some(next @ ('?' | '$' | '#' | '@' | '*' | '0'..='9')) => {
+                            chars.next();
+                            next.to_string()
+                        }
+                        Some(next) if next == '_' || next.is_ascii_alphabetic() => {
+                            let mut name = String::new();
+                            while let Some(&c) = chars.peek() {
+                                if c != '_' && !c.is_ascii_alphanumeric() {
+                                    break;
+                                }
+                                name.push(c);
+                                chars.next();
+                            }
+                            name
+                        }
+                        _ => {
+                            parts.push(text(in_double, "$".to_string()));
+                            continue;
+                        }
+                    };
+                    if name == "@" && in_double && !heredoc {
+                        saw_at = true;
+                        for (i, arg) in self.positional.iter().enumerate() {
+                            if i > 0 {
+                                parts.push(Part::FieldBreak);
+                            }
+                            parts.push(Part::Quoted(arg.clone()));
+                        }
+                    } else if in_double {
+                        parts.push(Part::Quoted(self.lookup(&name)));
+                    } else {
+                        parts.push(Part::Expanded(self.lookup(&name)));
+                    }
+                }
+                _ => parts.push(text(in_double, c.to_string())),
+            }
+        }
+
+        parts
+    }
+
+    /// Whether an `exit`, `break`, `continue` or `return` is unwinding the current list.
+    fn unwinding(&self) -> bool {
+        self.exit_requested.is_some() || self.flow.is_some()
+    }
+
+    fn run_list(&mut self, list: &List) -> i32 {
+        for item in list {
+            if self.unwinding() {
+                break;
+            }
+            if item.background {
+                self.run_background(&item.and_or);
+            } else {
+                self.last_status = self.run_and_or(&item.and_or);
+            }
+        }
+        self.last_status
+    }
 
-    loop {
-        if let Some(cmd_buf) = get_next_command(last_command.as_deref()) {
-            last_command = Some(cmd_buf.clone());
-            if cmd_buf == "exit" {
+    fn run_and_or(&mut self, and_or: &AndOr) -> i32 {
+        let mut status = self.run_pipeline(&and_or.first);
+        for (connector, pipeline) in &and_or.rest {
+            if self.unwinding() {
                 break;
             }
+            self.last_status = status;
+            let run = match connector {
+                Connector::And => status == 0,
+                Connector::Or => status != 0,
+            };
+            if run {
+                status = self.run_pipeline(pipeline);
+            }
+        }
+        status
+    }
+
+    fn run_pipeline(&mut self, pipeline: &Pipeline) -> i32 {
+        let Some(format) = pipeline.time else {
+            return self.run_stages(&pipeline.cmds);
+        };
+
+        let outer = self.usage.replace(Usage::default());
+        let self_before = getrusage(libc::RUSAGE_SELF);
+        let start = Instant::now();
+
+        let status = self.run_stages(&pipeline.cmds);
+
+        let real = start.elapsed();
+        let self_after = getrusage(libc::RUSAGE_SELF);
+        let mut usage = self.usage.take().unwrap_or_default();
+        usage.user += timeval(self_after.ru_utime).saturating_sub(timeval(self_before.ru_utime));
+        usage.sys += timeval(self_after.ru_stime).saturating_sub(timeval(self_before.ru_stime));
+        usage.report(real, status, format);
+
+        self.usage = outer.map(|mut outer| {
+            outer.merge(&usage);
+            outer
+        });
+        status
+    }
+
+    /// Runs the commands of a pipeline, each in its own forked process when there are several.
+    fn run_stages(&mut self, cmds: &[Cmd]) -> i32 {
+        if cmds.len() == 1 {
+            return self.run_command(&cmds[0]);
+        }
+
+        let mut pids = Vec::new();
+        let mut input: Option<OwnedFd> = None;
+        for (i, cmd) in cmds.iter().enumerate() {
+            let (read, write) = if i + 1 < cmds.len() {
+                match make_pipe() {
+                    Ok((read, write)) => (Some(read), Some(write)),
+                    Err(e) => {
+                        eprintln!("mysh: pipe: {}", e);
+                        break;
+                    }
+                }
+            } else {
+                (None, None)
+            };
+            let next_input = read.as_ref().map(|fd| fd.as_raw_fd());
+            let stdin = input.take();
+            let cmd = cmd.clone();
+            let pid = self.fork_shell(move |sh| {
+                unsafe {
+                    if let Some(fd) = next_input {
+                        libc::close(fd);
+                    }
+                    if let Some(fd) = &stdin {
+                        libc::dup2(fd.as_raw_fd(), libc::STDIN_FILENO);
+                    }
+                    if let Some(fd) = &write {
+                        libc::dup2(fd.as_raw_fd(), libc::STDOUT_FILENO);
+                    }
+                }
+                drop((stdin, write));
+                sh.run_command(&cmd)
+            });
+            pids.extend(pid);
+            input = read;
+        }
+        drop(input);
+
+        let mut status = 1;
+        for pid in pids {
+            status = self.wait_child(pid);
+        }
+        status
+    }
+
+    fn run_command(&mut self, cmd: &Cmd) -> i32 {
+        if cmd.redirects.is_empty() {
+            return self.run_node(&cmd.node);
+        }
+        let redirected = match self.apply_redirects(&cmd.redirects) {
+            Ok(redirected) => redirected,
+            Err(e) => {
+                eprintln!("mysh: {}", e);
+                return 1;
+            }
+        };
+        let status = self.run_node(&cmd.node);
+        redirected.restore();
+        status
+    }
+
+    fn run_node(&mut self, node: &Node) -> i32 {
+        match node {
+            Node::Simple { assigns, words } => self.run_simple(assigns, words),
+            Node::Subshell(list) => self.run_subshell(list),
+            Node::Group(list) => self.run_list(list),
+            Node::Not(cmd) => {
+                if self.run_command(cmd) == 0 { 1 } else { 0 }
+            }
+            Node::If { branches, otherwise } => {
+                for (cond, body) in branches {
+                    let status = self.run_list(cond);
+                    if self.unwinding() {
+                        return status;
+                    }
+                    if status == 0 {
+                        return self.run_list(body);
+                    }
+                }
+                match otherwise {
+                    Some(body) => self.run_list(body),
+                    None => 0,
+                }
+            }
+            Node::While { cond, body, until } => {
+                let mut status = 0;
+                self.loop_depth += 1;
+                loop {
+                    let cond_status = self.run_list(cond);
+                    if self.loop_should_stop() || (cond_status == 0) == *until {
+                        break;
+                    }
+                    status = self.run_list(body);
+                    if self.loop_should_stop() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            Node::For { var, words, body } => {
+                let items = match words {
+                    Some(words) => self.expand_fields(words),
+                    None => self.positional.clone(),
+                };
+                let mut status = 0;
+                self.loop_depth += 1;
+                for item in items {
+                    self.vars.insert(var.clone(), item);
+                    status = self.run_list(body);
+                    if self.loop_should_stop() {
+                        break;
+                    }
+                }
+                self.loop_depth -= 1;
+                status
+            }
+            Node::Case { word, arms } => {
+                let word = self.expand_word(word);
+                for arm in arms {
+                    if arm.patterns.iter().any(|p| glob_match(&self.expand_pattern(p), &word)) {
+                        return self.run_list(&arm.body);
+                    }
+                }
+                0
+            }
+            Node::Function { name, body } => {
+                self.functions.insert(name.clone(), Rc::clone(body));
+                0
+            }
+        }
+    }
+
+    /// Consumes a pending `break`/`continue` after a loop body; returns whether the loop ends.
+    fn loop_should_stop(&mut self) -> bool {
+        match self.flow {
+            Some(Flow::Break(n)) => {
+                self.flow = (n > 1).then(|| Flow::Break(n - 1));
+                true
+            }
+            Some(Flow::Continue(n)) if n > 1 => {
+                self.flow = Some(Flow::Continue(n - 1));
+                true
+            }
+            Some(Flow::Continue(_)) => {
+                self.flow = None;
+                false
+            }
+            Some(Flow::Return(_)) => true,
+            None => self.exit_requested.is_some(),
+        }
+    }
+
+    fn call_function(&mut self, body: &Cmd, args: &[String]) -> i32 {
+        let positional = std::mem::replace(&mut self.positional, args[1..].to_vec());
+        let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+        let mut status = self.run_command(body);
+        if let Some(Flow::Return(code)) = self.flow.take() {
+            status = code;
+        }
+        self.positional = positional;
+        self.loop_depth = loop_depth;
+        status
+    }
+
+    /// Points the shell's own stdin/stdout at the redirection targets, remembering the old fds.
+    fn apply_redirects(&self, redirects: &[Redirect]) -> io::Result<Redirected> {
+        let mut redirected = Redirected { saved: Vec::new(), writers: Vec::new() };
+        for redirect in redirects {
+            let opened = match redirect {
+                Redirect::Input(path) => {
+                    open_target(&self.expand_word(path), OpenOptions::new().read(true))
+                }
+                Redirect::Output(path) => open_target(
+                    &self.expand_word(path),
+                    OpenOptions::new().write(true).create(true).truncate(true),
+                ),
+                Redirect::Append(path) => {
+                    open_target(&self.expand_word(path), OpenOptions::new().append(true).create(true))
+                }
+                Redirect::HereDoc(doc) => {
+                    let body = if doc.expand { self.expand_heredoc(&doc.body) } else { doc.body.clone() };
+                    pipe_text(&body)
+                }
+                Redirect::HereString(word) => pipe_text(&format!("{}\n", self.expand_word(word))),
+            };
+            let (source, writer) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    redirected.restore();
+                    return Err(e);
+                }
+            };
+            redirected.writers.extend(writer);
+            let target = match redirect {
+                Redirect::Output(_) | Redirect::Append(_) => libc::STDOUT_FILENO,
+                _ => libc::STDIN_FILENO,
+            };
+            io::stdout().flush().ok();
+            let saved = unsafe { libc::fcntl(target, libc::F_DUPFD_CLOEXEC, 10) };
+            redirected.saved.push((target, saved));
+            unsafe { libc::dup2(source.as_raw_fd(), target) };
+        }
+        Ok(redirected)
+    }
+
+    /// Forks and runs `body` in the child with a copy of this shell, returning the child's pid.
+    fn fork_shell(&mut self, body: impl FnOnce(&mut Shell) -> i32) -> Option<libc::pid_t> {
+        io::stdout().flush().ok();
+        let pid = unsafe { libc::fork() };
+        match pid {
+            -1 => {
+                eprintln!("mysh: fork: {}", io::Error::last_os_error());
+                None
+            }
+            0 => {
+                let mut child = self.clone();
+                child.jobs.clear();
+                child.usage = None;
+                let status = body(&mut child);
+                let status = child.exit_requested.unwrap_or(status);
+                io::stdout().flush().ok();
+                unsafe { libc::_exit(status) }
+            }
+            pid => Some(pid),
+        }
+    }
 
-            let n_args = get_n_spaces(&cmd_buf) + 1;
-            let cmd_args = tokenize(&cmd_buf);
-            execute(cmd_args);
+    fn run_subshell(&mut self, list: &List) -> i32 {
+        let list = list.clone();
+        match self.fork_shell(move |sh| sh.run_list(&list)) {
+            Some(pid) => self.wait_child(pid),
+            None => 1,
         }
     }
-}
\ No newline at end of file
+
+    /// Waits for a child, adding its resource usage to a running `time` measurement.
+    fn wait_child(&mut self, pid: libc::pid_t) -> i32 {
+        let (status, rusage) = wait_pid(pid);
+        if let Some(usage) = &mut self.usage {
+            usage.add(&rusage);
+        }
+        status
+    }
+
+    fn run_background(&mut self, and_or: &AndOr) {
+        let and_or = and_or.clone();
+        if let Some(pid) = self.fork_shell(move |sh| sh.run_and_or(&and_or)) {
+            if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+                println!("[{}] {}", self.jobs.len() + 1, pid);
+            }
+            self.jobs.push(pid);
+        }
+        self.last_status = 0;
+    }
+
+    /// Reaps finished background jobs so they don't linger as zombies.
+    fn reap_jobs(&mut self) {
+        self.jobs.retain(|&pid| {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) == 0 }
+        });
+    }
+
+    fn run_simple(&mut self, assigns: &[(String, String)], words: &[String]) -> i32 {
+        let assigns: Vec<(String, String)> = assigns
+            .iter()
+            .map(|(name, value)| (name.clone(), self.expand_word(value)))
+            .collect();
+        let args = self.expand_fields(words);
+
+        if args.is_empty() {
+            self.vars.extend(assigns);
+            return 0;
+        }
+
+        let status = self.run_args(&assigns, &args);
+        if let Some(log) = &self.argv_log {
+            log.append(&args, status);
+        }
+        status
+    }
+
+    fn run_args(&mut self, assigns: &[(String, String)], args: &[String]) -> i32 {
+        if let Some(status) = self.run_builtin(args) {
+            return status;
+        }
+
+        if let Some(body) = self.functions.get(&args[0]).cloned() {
+            let saved: Vec<(String, Option<String>)> = assigns
+                .iter()
+                .map(|(name, value)| (name.clone(), self.vars.insert(name.clone(), value.clone())))
+                .collect();
+            let status = self.call_function(&body, args);
+            for (name, old) in saved.into_iter().rev() {
+                match old {
+                    Some(value) => self.vars.insert(name, value),
+                    None => self.vars.remove(&name),
+                };
+            }
+            return status;
+        }
+
+        let mut command = Command::new(&args[0]);
+        command.args(&args[1..]).env_clear().envs(self.environment(assigns));
+
+        match command.spawn() {
+            Ok(child) => self.wait_child(child.id() as libc::pid_t),
+            Err(e) => {
+                eprintln!("mysh: {}: {}", args[0], e);
+                if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 }
+            }
+        }
+    }
+
+    /// The environment of an external command: the exported variables that are set, and then
+    /// the command's own assignments. Nothing else is inherited, so `unset` and assignments
+    /// without `export` reach children as they would in sh.
+    fn environment(&self, assigns: &[(String, String)]) -> HashMap<String, String> {
+        let mut env: HashMap<String, String> = self
+            .exported
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.vars.get(name)?.clone())))
+            .collect();
+        env.extend(assigns.iter().cloned());
+        env
+    }
+
+    fn run_builtin(&mut self, args: &[String]) -> Option<i32> {
+        let status = match args[0].as_str() {
+            "exit" => {
+                let code = match args.get(1) {
+                    Some(arg) => arg.parse().unwrap_or(2),
+                    None => self.last_status,
+                };
+                self.exit_requested = Some(code);
+                code
+            }
+            "cd" => {
+                let dir = match args.get(1) {
+                    Some(dir) => dir.clone(),
+                    None => self.lookup("HOME"),
+                };
+                match std::env::set_current_dir(&dir) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        eprintln!("mysh: cd: {}: {}", dir, e);
+                        1
+                    }
+                }
+            }
+            "export" => {
+                for arg in &args[1..] {
+                    let name = match split_assignment(arg) {
+                        Some((name, value)) => {
+                            self.vars.insert(name.clone(), value);
+                            name
+                        }
+                        None => arg.clone(),
+                    };
+                    self.exported.insert(name);
+                }
+                0
+            }
+            "unset" => {
+                for name in &args[1..] {
+                    self.vars.remove(name);
+                    self.exported.remove(name);
+                    self.functions.remove(name);
+                }
+                0
+            }
+            "true" | ":" => 0,
+            "false" => 1,
+            "test" => eval_test(&args[1..]),
+            "[" => {
+                if args.last().map(String::as_str) != Some("]") {
+                    eprintln!("mysh: [: missing `]'");
+                    2
+                } else {
+                    eval_test(&args[1..args.len() - 1])
+                }
+            }
+            "break" | "continue" => {
+                let n = match args.get(1).map(|arg| arg.parse::<u32>()) {
+                    None => 1,
+                    Some(Ok(n)) if n > 0 => n,
+                    Some(_) => {
+                        eprintln!("mysh: {}: {}: loop count out of range", args[0], args[1]);
+                        return Some(1);
+                    }
+                };
+                if self.loop_depth == 0 {
+                    eprintln!("mysh: {}: only meaningful in a loop", args[0]);
+                    return Some(0);
+                }
+                let n = n.min(self.loop_depth);
+                self.flow = Some(if args[0] == "break" { Flow::Break(n) } else { Flow::Continue(n) });
+                0
+            }
+            "return" => {
+                let code = match args.get(1) {
+                    Some(arg) => arg.parse().unwrap_or(2),
+                    None => self.last_status,
+                };
+                self.flow = Some(Flow::Return(code));
+                code
+            }
+            "shift" => {
+                let n = args.get(1).and_then(|arg| arg.parse().ok()).unwrap_or(1);
+                if n > self.positional.len() {
+                    1
+                } else {
+                    self.positional.drain(..n);
+                    0
+                }
+            }
+            _ => return None,
+        };
+        Some(status)
+    }
+}
+
+fn join_parts(parts: Vec<Part>) -> String {
+    let mut out = String::new();
+    for part in parts {
+        match part {
+            Part::Literal(text) | Part::Quoted(text) | Part::Expanded(text) => out.push_str(&text),
+            Part::FieldBreak => out.push(' '),
+        }
+    }
+    out
+}
+
+/// A field being assembled during expansion, with a glob pattern that keeps quoted text literal.
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    started: bool,
+}
+
+impl Field {
+    fn push(&mut self, text: &str, pattern: &str) {
+        self.text.push_str(text);
+        self.pattern.push_str(pattern);
+        self.started = true;
+    }
+
+    fn finish(&mut self, fields: &mut Vec<String>) {
+        if !self.started {
+            return;
+        }
+        let field = std::mem::take(self);
+        if has_wildcard(&field.pattern) {
+            let matches = glob_paths(&field.pattern);
+            if !matches.is_empty() {
+                fields.extend(matches);
+                return;
+            }
+        }
+        fields.push(field.text);
+    }
+}
+
+fn escape_glob(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if "*?[]\\".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn has_wildcard(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Matches `text` against a shell pattern with `*`, `?`, `[...]` and backslash escapes.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_at(&pattern, &text)
+}
+
+fn glob_match_at(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| glob_match_at(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && glob_match_at(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let Some(&c) = text.first() else {
+                return false;
+            };
+            match match_bracket(&pattern[1..], c) {
+                Some((matched, len)) => matched && glob_match_at(&pattern[1 + len..], &text[1..]),
+                None => c == '[' && glob_match_at(&pattern[1..], &text[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match_at(&pattern[2..], &text[1..])
+        }
+        Some(&p) => text.first() == Some(&p) && glob_match_at(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Matches `c` against a bracket expression following a `[`. Returns whether it matched and
+/// how many pattern characters the expression used, or `None` if the bracket is unterminated.
+fn match_bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(pattern.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        if pattern[i] == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        let lo = pattern[i];
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= lo <= c && c <= pattern[i + 2];
+            i += 3;
+        } else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Expands a pathname pattern against the filesystem, returning the sorted matches.
+fn glob_paths(pattern: &str) -> Vec<String> {
+    let mut paths = vec![if pattern.starts_with('/') { "/".to_string() } else { String::new() }];
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut next = Vec::new();
+        for base in &paths {
+            let join = |name: &str| {
+                if base.is_empty() || base.ends_with('/') {
+                    format!("{}{}", base, name)
+                } else {
+                    format!("{}/{}", base, name)
+                }
+            };
+            if !has_wildcard(component) {
+                next.push(join(&remove_quotes(component)));
+                continue;
+            }
+            let Ok(entries) = std::fs::read_dir(if base.is_empty() { "." } else { base }) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if glob_match(component, &name) {
+                    next.push(join(&name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|path| std::fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths
+}
+
+/// Evaluates the arguments of `test`/`[`: 0 if true, 1 if false, 2 on a usage error.
+fn eval_test(args: &[String]) -> i32 {
+    let mut test = TestExpr { args, pos: 0 };
+    let result = test.parse_or();
+    match result {
+        Ok(_) if test.pos < args.len() => {
+            eprintln!("mysh: test: too many arguments");
+            2
+        }
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("mysh: test: {}", e);
+            2
+        }
+    }
+}
+
+const TEST_UNARY: [&str; 19] = [
+    "-e", "-f", "-d", "-L", "-h", "-p", "-S", "-b", "-c", "-s", "-r", "-w", "-x", "-z", "-n", "-t", "-g", "-u",
+    "-k",
+];
+
+const TEST_BINARY: [&str; 14] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+/// A recursive-descent evaluator for `test` expressions.
+struct TestExpr<'a> {
+    args: &'a [String],
+    pos: usize,
+}
+
+impl TestExpr<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.args.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        let arg = self.args.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn parse_or(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_and()?;
+        while self.peek() == Some("-o") {
+            self.pos += 1;
+            result |= self.parse_and()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<bool, String> {
+        let mut result = self.parse_not()?;
+        while self.peek() == Some("-a") {
+            self.pos += 1;
+            result &= self.parse_not()?;
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<bool, String> {
+        if self.peek() == Some("!") && self.pos + 1 < self.args.len() {
+            self.pos += 1;
+            return Ok(!self.parse_not()?);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<bool, String> {
+        let remaining = self.args.len() - self.pos;
+        if remaining == 0 {
+            return Ok(false);
+        }
+
+        let binary = self.args.get(self.pos + 1).filter(|op| TEST_BINARY.contains(&op.as_str()));
+        if remaining >= 3 && binary.is_some() {
+            let left = self.next()?.to_string();
+            let op = self.next()?.to_string();
+            let right = self.next()?.to_string();
+            return test_binary(&left, &op, &right);
+        }
+
+        let arg = self.next()?.to_string();
+        if arg == "(" && remaining >= 3 {
+            let result = self.parse_or()?;
+            if self.next()? != ")" {
+                return Err("`)' expected".to_string());
+            }
+            return Ok(result);
+        }
+        if TEST_UNARY.contains(&arg.as_str()) && remaining >= 2 {
+            let operand = self.next()?.to_string();
+            return Ok(test_unary(&arg, &operand));
+        }
+        Ok(!arg.is_empty())
+    }
+}
+
+fn test_unary(op: &str, operand: &str) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let access = |mode| {
+        std::ffi::CString::new(operand)
+            .map(|path| unsafe { libc::access(path.as_ptr(), mode) } == 0)
+            .unwrap_or(false)
+    };
+    let meta = std::fs::metadata(operand);
+    match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-t" => operand.parse().is_ok_and(|fd| unsafe { libc::isatty(fd) } == 1),
+        "-L" | "-h" => std::fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-r" => access(libc::R_OK),
+        "-w" => access(libc::W_OK),
+        "-x" => access(libc::X_OK),
+        _ => match meta {
+            Err(_) => false,
+            Ok(meta) => match op {
+                "-e" => true,
+                "-f" => meta.is_file(),
+                "-d" => meta.is_dir(),
+                "-p" => meta.file_type().is_fifo(),
+                "-S" => meta.file_type().is_socket(),
+                "-b" => meta.file_type().is_block_device(),
+                "-c" => meta.file_type().is_char_device(),
+                "-s" => meta.len() > 0,
+                "-g" => meta.mode() & libc::S_ISGID != 0,
+                "-u" => meta.mode() & libc::S_ISUID != 0,
+                "-k" => meta.mode() & libc::S_ISVTX != 0,
+                _ => false,
+            },
+        },
+    }
+}
+
+fn test_binary(left: &str, op: &str, right: &str) -> Result<bool, String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let int = |s: &str| {
+        s.trim().parse::<i64>().map_err(|_| format!("{}: integer expression expected", s))
+    };
+    let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" => int(left)? == int(right)?,
+        "-ne" => int(left)? != int(right)?,
+        "-lt" => int(left)? < int(right)?,
+        "-le" => int(left)? <= int(right)?,
+        "-gt" => int(left)? > int(right)?,
+        "-ge" => int(left)? >= int(right)?,
+        "-nt" => matches!((modified(left), modified(right)), (Some(l), Some(r)) if l > r),
+        "-ot" => matches!((modified(left), modified(right)), (Some(l), Some(r)) if l < r),
+        _ => match (std::fs::metadata(left), std::fs::metadata(right)) {
+            (Ok(l), Ok(r)) => l.dev() == r.dev() && l.ino() == r.ino(),
+            _ => false,
+        },
+    })
+}
+
+/// File descriptors replaced by redirections, and the processes feeding here-documents.
+struct Redirected {
+    saved: Vec<(i32, i32)>,
+    writers: Vec<libc::pid_t>,
+}
+
+impl Redirected {
+    fn restore(self) {
+        io::stdout().flush().ok();
+        for &(target, saved) in self.saved.iter().rev() {
+            unsafe {
+                if saved == -1 {
+                    libc::close(target);
+                } else {
+                    libc::dup2(saved, target);
+                    libc::close(saved);
+                }
+            }
+        }
+        for pid in self.writers {
+            wait_pid(pid);
+        }
+    }
+}
+
+fn open_target(path: &str, options: &OpenOptions) -> io::Result<(OwnedFd, Option<libc::pid_t>)> {
+    match options.open(path) {
+        Ok(file) => Ok((file.into(), None)),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}: {}", path, e))),
+    }
+}
+
+fn make_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Returns the read end of a pipe that a forked writer process fills with `text`.
+fn pipe_text(text: &str) -> io::Result<(OwnedFd, Option<libc::pid_t>)> {
+    let (read, write) = make_pipe()?;
+
+    io::stdout().flush().ok();
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            drop(read);
+            let ok = File::from(write).write_all(text.as_bytes()).is_ok();
+            unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+        }
+        pid => Ok((read, Some(pid))),
+    }
+}
+
+/// Waits for `pid` with `wait4`, returning its exit status and resource usage.
+fn wait_pid(pid: libc::pid_t) -> (i32, libc::rusage) {
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } == -1 {
+        eprintln!("mysh: wait4: {}", io::Error::last_os_error());
+        return (1, rusage);
+    }
+    let status = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    };
+    (status, rusage)
+}
+
+fn getrusage(who: libc::c_int) -> libc::rusage {
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(who, &mut rusage) };
+    rusage
+}
+
+fn timeval(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+/// Resources used by the processes of a `time` pipeline.
+#[derive(Debug, Clone, Copy, Default)]
+struct Usage {
+    user: Duration,
+    sys: Duration,
+    max_rss_kb: i64,
+    voluntary_switches: i64,
+    involuntary_switches: i64,
+}
+
+impl Usage {
+    fn add(&mut self, rusage: &libc::rusage) {
+        self.user += timeval(rusage.ru_utime);
+        self.sys += timeval(rusage.ru_stime);
+        self.max_rss_kb = self.max_rss_kb.max(rusage.ru_maxrss);
+        self.voluntary_switches += rusage.ru_nvcsw;
+        self.involuntary_switches += rusage.ru_nivcsw;
+    }
+
+    fn merge(&mut self, other: &Usage) {
+        self.user += other.user;
+        self.sys += other.sys;
+        self.max_rss_kb = self.max_rss_kb.max(other.max_rss_kb);
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+
+    /// Prints the report to stderr, like the `time` keyword in other shells.
+    fn report(&self, real: Duration, status: i32, format: TimeFormat) {
+        let report = self.format_report(real, status, format);
+        io::stdout().flush().ok();
+        eprintln!("{}", report);
+    }
+
+    fn format_report(&self, real: Duration, status: i32, format: TimeFormat) -> String {
+        match format {
+            TimeFormat::Json => {
+                let report = TimeReport {
+                    real: real.as_secs_f64(),
+                    user: self.user.as_secs_f64(),
+                    sys: self.sys.as_secs_f64(),
+                    max_rss_kb: self.max_rss_kb,
+                    voluntary_ctxsw: self.voluntary_switches,
+                    involuntary_ctxsw: self.involuntary_switches,
+                    status,
+                };
+                serde_json::to_string(&report).unwrap_or_default()
+            }
+            TimeFormat::Posix => format!(
+                "real {:.2}\nuser {:.2}\nsys {:.2}",
+                real.as_secs_f64(),
+                self.user.as_secs_f64(),
+                self.sys.as_secs_f64()
+            ),
+            TimeFormat::Default => format!(
+                "\nreal\t{}\nuser\t{}\nsys\t{}\nmaxrss\t{} KB\nctxsw\t{} voluntary, {} involuntary",
+                minutes_seconds(real),
+                minutes_seconds(self.user),
+                minutes_seconds(self.sys),
+                self.max_rss_kb,
+                self.voluntary_switches,
+                self.involuntary_switches
+            ),
+        }
+    }
+}
+
+/// The report `time --json` writes, one line of JSON with times in seconds.
+#[derive(Debug, Serialize)]
+struct TimeReport {
+    real: f64,
+    user: f64,
+    sys: f64,
+    max_rss_kb: i64,
+    voluntary_ctxsw: i64,
+    involuntary_ctxsw: i64,
+    status: i32,
+}
+
+/// Formats a duration as `0m1.234s`.
+fn minutes_seconds(d: Duration) -> String {
+    let secs = d.as_secs_f64();
+    format!("{}m{:.3}s", (secs / 60.0).floor(), secs % 60.0)
+}
+
+/// One input line of a session, as written by `--record` and checked by `--replay`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    input: String,
+    commands: Vec<CommandRecord>,
+    status: i32,
+    elapsed_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+}
+
+/// A simple command run while recording, with its expanded argv.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CommandRecord {
+    argv: Vec<String>,
+    status: i32,
+}
+
+/// An unlinked temporary file that every process of the session appends command records to,
+/// so that pipeline stages and subshells running in forked children are recorded too.
+struct ArgvLog {
+    file: File,
+}
+
+/// Creates a temporary file, opened for reading and appending, and unlinks it straight away.
+fn unlinked_temp_file(name: &str) -> io::Result<File> {
+    static CREATED: AtomicU32 = AtomicU32::new(0);
+    let n = CREATED.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("mysh-{}-{}-{}", name, std::process::id(), n));
+    let file = OpenOptions::new().read(true).append(true).create_new(true).open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+impl ArgvLog {
+    fn create() -> io::Result<Self> {
+        Ok(ArgvLog { file: unlinked_temp_file("argv")? })
+    }
+
+    fn append(&self, argv: &[String], status: i32) {
+        let record = CommandRecord { argv: argv.to_vec(), status };
+        if let Ok(mut line) = serde_json::to_string(&record) {
+            line.push('\n');
+            // A single O_APPEND write keeps lines from different processes intact.
+            (&self.file).write_all(line.as_bytes()).ok();
+        }
+    }
+
+    /// Returns the records logged so far and empties the log.
+    fn take(&self) -> Vec<CommandRecord> {
+        let mut contents = String::new();
+        (&self.file).seek(SeekFrom::Start(0)).ok();
+        (&self.file).read_to_string(&mut contents).ok();
+        self.file.set_len(0).ok();
+        contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect()
+    }
+}
+
+/// Redirects the shell's stdout and stderr into unlinked temporary files for one input line,
+/// optionally copying what was captured through to the original descriptors afterwards.
+///
+/// Files need no reader while the line runs, so the shell stays single-threaded: the children
+/// it forks to run its own code (subshells, pipeline stages, here-document writers) can't
+/// inherit a lock some other thread was holding. A background job that outlives the line
+/// just goes on writing to a file nobody reads.
+struct Capture {
+    saved: [i32; 2],
+    files: [File; 2],
+    tee: bool,
+}
+
+impl Capture {
+    fn start(tee: bool) -> io::Result<Self> {
+        io::stdout().flush().ok();
+        let files = [unlinked_temp_file("stdout")?, unlinked_temp_file("stderr")?];
+        let mut saved = [-1; 2];
+        for (i, fd) in [libc::STDOUT_FILENO, libc::STDERR_FILENO].into_iter().enumerate() {
+            saved[i] = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) };
+            unsafe { libc::dup2(files[i].as_raw_fd(), fd) };
+        }
+        Ok(Capture { saved, files, tee })
+    }
+
+    /// Restores stdout and stderr and returns what was written to them.
+    fn finish(self) -> (String, String) {
+        io::stdout().flush().ok();
+        let fds = [libc::STDOUT_FILENO, libc::STDERR_FILENO];
+        let [stdout, stderr] = [0, 1].map(|i| {
+            let fd = fds[i];
+            unsafe {
+                libc::dup2(self.saved[i], fd);
+                libc::close(self.saved[i]);
+            }
+            let mut file = &self.files[i];
+            let mut captured = Vec::new();
+            file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_end(&mut captured)).ok();
+            if self.tee {
+                // Straight to the descriptor, which stays open when `copy` goes away.
+                let mut copy = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+                copy.write_all(&captured).ok();
+            }
+            String::from_utf8_lossy(&captured).into_owned()
+        });
+        (stdout, stderr)
+    }
+}
+
+impl Shell {
+    /// Runs one input line, collecting everything `--record` stores about it.
+    fn run_recorded(&mut self, input: &str, capture: bool, tee: bool) -> Record {
+        if self.argv_log.is_none() {
+            match ArgvLog::create() {
+                Ok(log) => self.argv_log = Some(Rc::new(log)),
+                Err(e) => eprintln!("mysh: cannot create command log: {}", e),
+            }
+        }
+        let capture = match capture {
+            true => Capture::start(tee).map_err(|e| eprintln!("mysh: cannot capture output: {}", e)).ok(),
+            false => None,
+        };
+
+        let start = Instant::now();
+        run_script(self, input);
+        let elapsed = start.elapsed();
+
+        let (stdout, stderr) = match capture {
+            Some(capture) => {
+                let (stdout, stderr) = capture.finish();
+                (Some(stdout), Some(stderr))
+            }
+            None => (None, None),
+        };
+        Record {
+            input: input.to_string(),
+            commands: self.argv_log.as_ref().map(|log| log.take()).unwrap_or_default(),
+            status: self.last_status,
+            elapsed_ms: elapsed.as_secs_f64() * 1000.0,
+            stdout,
+            stderr,
+        }
+    }
+}
+
+/// The shell's builtins, none of which read from the terminal.
+const BUILTINS: [&str; 13] = [
+    "exit", "cd", "export", "unset", "true", ":", "false", "test", "[", "break", "continue", "return", "shift",
+];
+
+impl Shell {
+    /// Whether running `source` could hand the shell's stdin to a program: whether some command
+    /// other than a builtin would run without its input redirected or piped from another.
+    /// Functions are looked into; anything else is assumed to read its input.
+    fn reads_terminal(&self, source: &str) -> bool {
+        parse(source).is_ok_and(|list| self.list_reads_terminal(&list, &mut Vec::new()))
+    }
+
+    /// `calling` holds the functions being looked into, so that recursion ends.
+    fn list_reads_terminal(&self, list: &List, calling: &mut Vec<String>) -> bool {
+        list.iter().any(|item| {
+            let and_or = &item.and_or;
+            let mut pipelines = std::iter::once(&and_or.first).chain(and_or.rest.iter().map(|(_, p)| p));
+            pipelines.any(|pipeline| self.cmd_reads_terminal(&pipeline.cmds[0], calling))
+        })
+    }
+
+    fn cmd_reads_terminal(&self, cmd: &Cmd, calling: &mut Vec<String>) -> bool {
+        let input = |redirect: &Redirect| {
+            matches!(redirect, Redirect::Input(_) | Redirect::HereDoc(_) | Redirect::HereString(_))
+        };
+        if cmd.redirects.iter().any(input) {
+            return false;
+        }
+        let lists: Vec<&List> = match &cmd.node {
+            Node::Simple { words, .. } => {
+                let Some(name) = words.first() else {
+                    return false;
+                };
+                if BUILTINS.contains(&name.as_str()) || calling.contains(name) {
+                    return false;
+                }
+                let Some(body) = self.functions.get(name) else {
+                    return true;
+                };
+                calling.push(name.clone());
+                let reads = self.cmd_reads_terminal(body, calling);
+                calling.pop();
+                return reads;
+            }
+            Node::Not(cmd) => return self.cmd_reads_terminal(cmd, calling),
+            Node::Function { .. } => return false,
+            Node::Subshell(list) | Node::Group(list) => vec![list],
+            Node::If { branches, otherwise } => {
+                branches.iter().flat_map(|(cond, body)| [cond, body]).chain(otherwise).collect()
+            }
+            Node::While { cond, body, .. } => vec![cond, body],
+            Node::For { body, .. } => vec![body],
+            Node::Case { arms, .. } => arms.iter().map(|arm| &arm.body).collect(),
+        };
+        lists.into_iter().any(|list| self.list_reads_terminal(list, calling))
+    }
+}
+
+/// Runs the interactive loop, appending a record of every input line to `path`. When stdin is
+/// a terminal, only lines that can't read from it have their output captured.
+fn record(shell: &mut Shell, path: &str) {
+    let mut out = match File::create(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("mysh: {}: {}", path, e);
+            exit(1);
+        }
+    };
+    let terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    let mut last_command: Option<String> = None;
+
+    while shell.exit_requested.is_none() {
+        shell.reap_jobs();
+        let cmd_buf = match get_next_command(last_command.as_deref()) {
+            Input::Line(line) => line,
+            Input::Skip => continue,
+            Input::Eof => break,
+        };
+        last_command = Some(cmd_buf.clone());
+        // Lines that might prompt or draw on the terminal aren't captured, so that what they
+        // write reaches it as they write it.
+        let capture = !terminal || !shell.reads_terminal(&cmd_buf);
+        let record = shell.run_recorded(&cmd_buf, capture, true);
+        shell.flow = None;
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                if let Err(e) = writeln!(out, "{}", line) {
+                    eprintln!("mysh: {}: {}", path, e);
+                }
+            }
+            Err(e) => eprintln!("mysh: cannot record `{}': {}", cmd_buf, e),
+        }
+    }
+}
+
+/// Re-runs the inputs of a recorded session and reports every difference from the recording.
+/// Returns the number of lines whose results differ.
+fn replay(shell: &mut Shell, path: &str) -> usize {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("mysh: {}: {}", path, e);
+            exit(2);
+        }
+    };
+
+    let mut mismatches = 0;
+    let mut replayed = 0;
+    for (n, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let expected: Record = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("mysh: {}:{}: {}", path, n + 1, e);
+                exit(2);
+            }
+        };
+        let actual = shell.run_recorded(&expected.input, expected.stdout.is_some(), false);
+        shell.flow = None;
+        replayed += 1;
+
+        let mut report = Vec::new();
+        if actual.status != expected.status {
+            report.push(format!("  status: expected {}, got {}", expected.status, actual.status));
+        }
+        if actual.commands != expected.commands {
+            report.push("  commands:".to_string());
+            let show = |commands: &[CommandRecord]| {
+                commands.iter().map(|c| format!("{:?} => {}", c.argv, c.status)).collect::<Vec<_>>().join("\n")
+            };
+            report.extend(diff_lines(&show(&expected.commands), &show(&actual.commands)));
+        }
+        for (name, expected, actual) in
+            [("stdout", &expected.stdout, &actual.stdout), ("stderr", &expected.stderr, &actual.stderr)]
+        {
+            if let (Some(expected), Some(actual)) = (expected, actual)
+                && expected != actual
+            {
+                report.push(format!("  {}:", name));
+                report.extend(diff_lines(expected, actual));
+            }
+        }
+
+        if !report.is_empty() {
+            mismatches += 1;
+            println!("line {}: `{}'", n + 1, expected.input);
+            for line in report {
+                println!("{}", line);
+            }
+        }
+        if shell.exit_requested.is_some() {
+            break;
+        }
+    }
+
+    println!("replayed {} line(s), {} mismatch(es)", replayed, mismatches);
+    mismatches
+}
+
+/// A line diff based on the longest common subsequence, in `-expected`/`+actual` form.
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(format!("    {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("  - {}", a[i]));
+            i += 1;
+        } else {
+            out.push(format!("  + {}", b[j]));
+            j += 1;
+        }
+    }
+    if expected.ends_with('\n') != actual.ends_with('\n') {
+        out.push("  (trailing newline differs)".to_string());
+    }
+    out
+}
+
+/// Parses and runs a whole script, as for `mysh script.sh` or `mysh -c '...'`.
+fn run_script(shell: &mut Shell, source: &str) {
+    match parse(source) {
+        Ok(list) => {
+            shell.run_list(&list);
+        }
+        Err(e) => {
+            eprintln!("mysh: {}", e);
+            shell.last_status = 2;
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if let [_, flag, path] = args.as_slice()
+        && (flag == "--record" || flag == "--replay")
+    {
+        let mut shell = Shell::new(args[0].clone(), Vec::new());
+        if flag == "--record" {
+            record(&mut shell, path);
+            exit(shell.exit_requested.unwrap_or(shell.last_status));
+        }
+        exit(if replay(&mut shell, path) == 0 { 0 } else { 1 });
+    }
+
+    if args.len() > 1 {
+        let (source, script_name, positional) = if args[1] == "-c" {
+            let Some(command) = args.get(2) else {
+                eprintln!("mysh: -c: option requires an argument");
+                exit(2);
+            };
+            let name = args.get(3).cloned().unwrap_or_else(|| args[0].clone());
+            (command.clone(), name, args.iter().skip(4).cloned().collect())
+        } else {
+            match std::fs::read_to_string(&args[1]) {
+                Ok(source) => (source, args[1].clone(), args[2..].to_vec()),
+                Err(e) => {
+                    eprintln!("mysh: {}: {}", args[1], e);
+                    exit(127);
+                }
+            }
+        };
+        let mut shell = Shell::new(script_name, positional);
+        run_script(&mut shell, &source);
+        exit(shell.exit_requested.unwrap_or(shell.last_status));
+    }
+
+    let mut shell = Shell::new(args[0].clone(), Vec::new());
+    let mut last_command: Option<String> = None;
+
+    while shell.exit_requested.is_none() {
+        shell.reap_jobs();
+        let cmd_buf = match get_next_command(last_command.as_deref()) {
+            Input::Line(line) => line,
+            Input::Skip => continue,
+            Input::Eof => break,
+        };
+        last_command = Some(cmd_buf.clone());
+        run_script(&mut shell, &cmd_buf);
+        shell.flow = None;
+    }
+
+    exit(shell.exit_requested.unwrap_or(shell.last_status));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell() -> Shell {
+        Shell::new("mysh".to_string(), Vec::new())
+    }
+
+    #[test]
+    fn environment_is_the_exported_variables() {
+        let mut sh = shell();
+        run_script(&mut sh, "unset HOME; A=1; export B=2; export C; C=3; D=4; export E");
+        let env = sh.environment(&[("D".to_string(), "5".to_string()), ("B".to_string(), "6".to_string())]);
+        assert!(!env.contains_key("HOME"));
+        assert!(!env.contains_key("A"));
+        assert!(!env.contains_key("E"), "exported but never set");
+        assert_eq!(env.get("B").map(String::as_str), Some("6"));
+        assert_eq!(env.get("C").map(String::as_str), Some("3"));
+        assert_eq!(env.get("D").map(String::as_str), Some("5"));
+        // Assignments only last for the command.
+        assert_eq!(sh.environment(&[]).get("B").map(String::as_str), Some("2"));
+    }
+
+    /// The single command that `source` parses to.
+    fn command(source: &str) -> Cmd {
+        let mut list = parse(source).unwrap_or_else(|e| panic!("{source:?}: {e}"));
+        assert_eq!(list.len(), 1, "{source:?}");
+        let pipeline = list.remove(0).and_or.first;
+        assert_eq!(pipeline.cmds.len(), 1, "{source:?}");
+        pipeline.cmds.into_iter().next().unwrap()
+    }
+
+    fn words(cmd: &Cmd) -> Vec<&str> {
+        match &cmd.node {
+            Node::Simple { words, .. } => words.iter().map(String::as_str).collect(),
+            node => panic!("not a simple command: {node:?}"),
+        }
+    }
+
+    #[test]
+    fn parse_if() {
+        let source = "if a; then b; elif c\nthen d; d2; else e; fi";
+        let Node::If { branches, otherwise } = command(source).node else {
+            panic!("not an if");
+        };
+        let shape: Vec<_> = branches.iter().map(|(cond, body)| (cond.len(), body.len())).collect();
+        assert_eq!(shape, [(1, 1), (1, 2)]);
+        assert_eq!(otherwise.map(|list| list.len()), Some(1));
+
+        let Node::If { branches, otherwise } = command("if a\nthen\n  b\nfi").node else {
+            panic!("not an if");
+        };
+        assert_eq!(branches.len(), 1);
+        assert!(otherwise.is_none());
+    }
+
+    #[test]
+    fn parse_while_and_until() {
+        let Node::While { cond, body, until } = command("while a; b; do c; done").node else {
+            panic!("not a while");
+        };
+        assert_eq!((cond.len(), body.len(), until), (2, 1, false));
+        let Node::While { until, .. } = command("until a\ndo\n  b\ndone").node else {
+            panic!("not an until");
+        };
+        assert!(until);
+    }
+
+    #[test]
+    fn parse_for() {
+        let Node::For { var, words, body } = command("for x in 1 \"2 3\" $y; do echo $x; done").node else {
+            panic!("not a for");
+        };
+        assert_eq!(var, "x");
+        assert_eq!(words, Some(vec!["1".to_string(), "\"2 3\"".to_string(), "$y".to_string()]));
+        assert_eq!(body.len(), 1);
+        // Without `in`, the loop runs over the positional parameters.
+        let Node::For { words, .. } = command("for x\ndo :; done").node else {
+            panic!("not a for");
+        };
+        assert_eq!(words, None);
+    }
+
+    #[test]
+    fn parse_case() {
+        let Node::Case { word, arms } = command("case $x in a|b) echo 1;; (c) echo 2;;\n*) ;; esac").node
+        else {
+            panic!("not a case");
+        };
+        assert_eq!(word, "$x");
+        let shape: Vec<_> = arms.iter().map(|arm| (arm.patterns.join("|"), arm.body.len())).collect();
+        assert_eq!(shape, [("a|b".to_string(), 1), ("c".to_string(), 1), ("*".to_string(), 0)]);
+        // The last arm's `;;` is optional.
+        let Node::Case { arms, .. } = command("case x in\n  x) echo\nesac").node else {
+            panic!("not a case");
+        };
+        assert_eq!(arms.len(), 1);
+    }
+
+    #[test]
+    fn parse_function() {
+        let Node::Function { name, body } = command("greet() {\n  echo hi; echo there\n}").node else {
+            panic!("not a function");
+        };
+        assert_eq!(name, "greet");
+        assert!(matches!(&body.node, Node::Group(list) if list.len() == 2));
+        let Node::Function { body, .. } = command("sub() (cd /)").node else {
+            panic!("not a function");
+        };
+        assert!(matches!(body.node, Node::Subshell(_)));
+    }
+
+    #[test]
+    fn parse_subshell_and_group() {
+        let cmd = command("(a; b) > out");
+        assert!(matches!(&cmd.node, Node::Subshell(list) if list.len() == 2));
+        assert!(matches!(&cmd.redirects[..], [Redirect::Output(target)] if target == "out"));
+        assert!(matches!(command("{ a; b & }").node, Node::Group(list) if list[1].background));
+    }
+
+    #[test]
+    fn parse_not() {
+        let Node::Not(inner) = command("! grep -q x file").node else {
+            panic!("not a `!'");
+        };
+        assert_eq!(words(&inner), ["grep", "-q", "x", "file"]);
+        let Node::Not(inner) = command("! ( false )").node else {
+            panic!("not a `!'");
+        };
+        assert!(matches!(inner.node, Node::Subshell(_)));
+    }
+
+    #[test]
+    fn parse_heredocs() {
+        let cmd = command("cat <<EOF > out\nhello $name\n  indented\nEOF");
+        assert_eq!(words(&cmd), ["cat"]);
+        let [Redirect::HereDoc(doc), Redirect::Output(_)] = &cmd.redirects[..] else {
+            panic!("redirects: {:?}", cmd.redirects);
+        };
+        assert_eq!(*doc, HereDoc { body: "hello $name\n  indented\n".to_string(), expand: true });
+
+        // A quoted delimiter turns off expansion; `<<-` strips leading tabs.
+        let cmd = command("cat <<-'END'\n\t$literal\n\tEND");
+        let [Redirect::HereDoc(doc)] = &cmd.redirects[..] else {
+            panic!("redirects: {:?}", cmd.redirects);
+        };
+        assert_eq!(*doc, HereDoc { body: "$literal\n".to_string(), expand: false });
+
+        // Bodies follow the line in the order their operators appear.
+        let list = parse("cat <<A; cat <<B\none\nA\ntwo\nB\n").unwrap();
+        let bodies: Vec<_> = list
+            .iter()
+            .map(|item| match &item.and_or.first.cmds[0].redirects[..] {
+                [Redirect::HereDoc(doc)] => doc.body.as_str(),
+                redirects => panic!("redirects: {redirects:?}"),
+            })
+            .collect();
+        assert_eq!(bodies, ["one\n", "two\n"]);
+    }
+
+    #[test]
+    fn parse_time() {
+        let formats = [
+            ("true", None),
+            ("time true", Some(TimeFormat::Default)),
+            ("time -p true", Some(TimeFormat::Posix)),
+            ("time --json true", Some(TimeFormat::Json)),
+            ("time -p --json a | b", Some(TimeFormat::Json)),
+        ];
+        for (source, expected) in formats {
+            let list = parse(source).unwrap_or_else(|e| panic!("{source:?}: {e}"));
+            assert_eq!(list[0].and_or.first.time, expected, "{source:?}");
+        }
+        let list = parse("time --json a | b").unwrap();
+        assert_eq!(list[0].and_or.first.cmds.len(), 2);
+        // After the options, `time` takes the rest of the pipeline as it is.
+        let list = parse("time -p -p echo -p").unwrap();
+        assert_eq!(words(&list[0].and_or.first.cmds[0]), ["echo", "-p"]);
+    }
+
+    #[test]
+    fn time_reports() {
+        let usage = Usage {
+            user: Duration::from_micros(1_250_000),
+            sys: Duration::from_millis(20),
+            max_rss_kb: 4096,
+            voluntary_switches: 3,
+            involuntary_switches: 1,
+        };
+        let real = Duration::from_secs(61) + Duration::from_millis(500);
+        let report = usage.format_report(real, 7, TimeFormat::Json);
+        let json: serde_json::Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "real": 61.5,
+                "user": 1.25,
+                "sys": 0.02,
+                "max_rss_kb": 4096,
+                "voluntary_ctxsw": 3,
+                "involuntary_ctxsw": 1,
+                "status": 7,
+            })
+        );
+        assert_eq!(usage.format_report(real, 0, TimeFormat::Posix), "real 61.50\nuser 1.25\nsys 0.02");
+        let default = usage.format_report(real, 0, TimeFormat::Default);
+        assert!(default.starts_with("\nreal\t1m1.500s\nuser\t0m1.250s\nsys\t0m0.020s\n"), "{default:?}");
+    }
+
+    #[test]
+    fn parse_errors() {
+        // Input that more lines could complete.
+        for source in [
+            "if true; then echo yes",
+            "if true; then echo yes; else",
+            "while true; do echo",
+            "for x in a b; do echo $x",
+            "case x in a) echo;;",
+            "f() {",
+            "cat <<EOF\nbody",
+            "echo 'open",
+        ] {
+            assert!(matches!(parse(source), Err(ParseError::Incomplete(_))), "{source:?}");
+        }
+        // Input that can't be completed.
+        for (source, near) in [
+            ("fi", "`fi'"),
+            ("if true; fi", "`fi'"),
+            ("while true; done", "`done'"),
+            ("for x in a; echo; done", "`echo'"),
+            ("for 1 in a; do :; done", "`1'"),
+            ("case x in a) echo; b) ;; esac", "`)'"),
+            ("( )", "`)'"),
+            ("echo a |", "end of file"),
+            ("echo >", "end of file"),
+            ("a && || b", "`||'"),
+        ] {
+            match parse(source) {
+                Err(e) => assert!(e.to_string().ends_with(near), "{source:?}: {e}"),
+                Ok(list) => panic!("{source:?} parsed as {list:?}"),
+            }
+        }
+    }
+
+    /// Runs `source` in a fresh shell and returns the value of each of `names` afterwards.
+    fn run(source: &str, names: &[&str]) -> Vec<String> {
+        let mut sh = shell();
+        run_script(&mut sh, source);
+        names.iter().map(|name| sh.lookup(name)).collect()
+    }
+
+    #[test]
+    fn last_status() {
+        assert_eq!(run("false; a=$?; true; b=$?", &["a", "b"]), ["1", "0"]);
+        assert_eq!(run("! true; a=$?; ! false; b=$?", &["a", "b"]), ["1", "0"]);
+        assert_eq!(run("false || a=$?; true && b=$?", &["a", "b"]), ["1", "0"]);
+        assert_eq!(run("[ 1 -lt 2 ]; a=$?; test -z x; b=$?; [ 1; c=$?", &["a", "b", "c"]), ["0", "1", "2"]);
+        assert_eq!(run("if false; then :; fi; a=$?", &["a"]), ["0"]);
+    }
+
+    #[test]
+    fn special_mode_bits() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("mysh-modes-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let modes = [("plain", 0o755), ("setuid", 0o4755), ("setgid", 0o2755), ("sticky", 0o1777)];
+        for (name, mode) in modes {
+            std::fs::write(dir.join(name), "").unwrap();
+            std::fs::set_permissions(dir.join(name), std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        // `u_plain` is the status of `test -u plain`, and so on.
+        let mut source = String::new();
+        let mut names = Vec::new();
+        for op in ["u", "g", "k"] {
+            for (name, _) in modes {
+                source += &format!("test -{op} {}/{name}; {op}_{name}=$?\n", dir.display());
+                names.push(format!("{op}_{name}"));
+            }
+        }
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let statuses = run(&source, &names);
+        std::fs::remove_dir_all(&dir).unwrap();
+        // -u, -g and -k against plain, setuid, setgid and sticky in turn.
+        assert_eq!(statuses, ["1", "0", "1", "1", "1", "1", "0", "1", "1", "1", "1", "0"]);
+        assert_eq!(run("[ -u /nonexistent ]; a=$?; [ -k ]; b=$?", &["a", "b"]), ["1", "0"]);
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let mut sh = Shell::new("script".to_string(), vec!["x".to_string(), "y z".to_string()]);
+        run_script(&mut sh, "n=$#; all=\"$@\"; first=$1; shift; after=$1; zero=$0");
+        let vars: Vec<_> = ["n", "all", "first", "after", "zero"].iter().map(|name| sh.lookup(name)).collect();
+        assert_eq!(vars, ["2", "x y z", "x", "y z", "script"]);
+
+        // A function call sees its own arguments and leaves the caller's alone.
+        let source = "f() { n=$#; all=\"$@\"; second=$2; }; f a \"b c\" d; outer=$#";
+        assert_eq!(run(source, &["n", "all", "second", "outer"]), ["3", "a b c d", "b c", "0"]);
+        let source = "count() { for arg; do m=\"$m.\"; done; }; count 1 2 3";
+        assert_eq!(run(source, &["m"]), ["..."]);
+    }
+
+    #[test]
+    fn return_from_functions() {
+        let source = "f() { a=1; return 3; a=2; }; f; s=$?";
+        assert_eq!(run(source, &["a", "s"]), ["1", "3"]);
+        // `return` unwinds the loops inside the function, and defaults to the last status.
+        let source = "f() { for i in 1 2 3; do while true; do n=$i; return 5; done; done; }; f; s=$?";
+        assert_eq!(run(source, &["n", "s"]), ["1", "5"]);
+        let source = "f() { false; return; }; f; s=$?";
+        assert_eq!(run(source, &["s"]), ["1"]);
+    }
+
+    #[test]
+    fn subshell_isolation() {
+        let source = "x=1; (x=2; y=3; f() { :; }; exit 4); s=$?; f; t=$?";
+        assert_eq!(run(source, &["x", "y", "s", "t"]), ["1", "", "4", "127"]);
+        // A group, by contrast, runs in the shell itself.
+        assert_eq!(run("x=1; { x=2; }", &["x"]), ["2"]);
+    }
+
+    #[test]
+    fn loops_and_case() {
+        let source = "for i in a b c; do if [ $i = b ]; then continue; fi; out=\"$out$i\"; done";
+        assert_eq!(run(source, &["out"]), ["ac"]);
+        let source = "n=; until [ \"$n\" = ... ]; do n=\"$n.\"; done";
+        assert_eq!(run(source, &["n"]), ["..."]);
+        let source = "for w in x.rs y.txt z; do
+            case $w in *.rs|*.c) k=\"${k}c\";; *.*) k=\"${k}d\";; *) k=\"${k}o\";; esac
+        done";
+        assert_eq!(run(source, &["k"]), ["cdo"]);
+    }
+
+    #[test]
+    fn lines_that_read_the_terminal() {
+        let mut sh = shell();
+        for source in ["ls", "x=1; vi", "cat | sort", "true && less", "if :; then read; fi", "! ssh", "($x)"] {
+            assert!(sh.reads_terminal(source), "{source:?}");
+        }
+        for source in [
+            "x=1; cd /; export x",
+            "sort < file",
+            "cat <<EOF | less\nbody\nEOF",
+            "grep x <<< \"$y\"",
+            "f() { vi; }",
+            "while false; do :; done",
+            "case x in *) true;; esac",
+            "for i in 1 2; do test -n $i; done",
+            "echo 'unterminated",
+        ] {
+            assert!(!sh.reads_terminal(source), "{source:?}");
+        }
+        run_script(&mut sh, "quiet() { true; }; loud() { echo; }; again() { again; }");
+        assert!(!sh.reads_terminal("quiet"));
+        assert!(sh.reads_terminal("loud"));
+        assert!(!sh.reads_terminal("again"));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// A first-in, first-out queue holding at most `capacity` items, shared between producer and
+/// consumer threads. `push` blocks while the buffer is full and `pop` while it is empty; the
+/// `try_` and `_timeout` variants give up instead of waiting, or after waiting too long.
+#[derive(Debug)]
+pub struct BoundedBuffer<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    /// Signalled when an item is popped, for producers waiting on a full buffer.
+    not_full: Condvar,
+    /// Signalled when an item is pushed, for consumers waiting on an empty buffer.
+    not_empty: Condvar,
+}
+
+impl<T> BoundedBuffer<T> {
+    /// An empty buffer with room for `capacity` items.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0, since nothing could ever be pushed.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "bounded buffer capacity must be at least 1");
+        BoundedBuffer {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            not_full: Condvar::new(),
+            not_empty: Condvar::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of items in the buffer, which other threads may change at any moment.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Adds `item` at the back, waiting for room while the buffer is full.
+    pub fn push(&self, item: T) {
+        let items = self.lock();
+        let mut items = self
+            .not_full
+            .wait_while(items, |items| items.len() == self.capacity)
+            .unwrap_or_else(PoisonError::into_inner);
+        self.push_back(&mut items, item);
+    }
+
+    /// Adds `item` at the back if there's room, or hands it back if the buffer is full.
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut items = self.lock();
+        if items.len() == self.capacity {
+            return Err(item);
+        }
+        self.push_back(&mut items, item);
+        Ok(())
+    }
+
+    /// Adds `item` at the back, waiting up to `timeout` for room, or hands it back if the
+    /// buffer is still full.
+    pub fn push_timeout(&self, item: T, timeout: Duration) -> Result<(), T> {
+        let items = self.lock();
+        let (mut items, _) = self
+            .not_full
+            .wait_timeout_while(items, timeout, |items| items.len() == self.capacity)
+            .unwrap_or_else(PoisonError::into_inner);
+        if items.len() == self.capacity {
+            return Err(item);
+        }
+        self.push_back(&mut items, item);
+        Ok(())
+    }
+
+    /// Removes the item at the front, waiting for one while the buffer is empty.
+    pub fn pop(&self) -> T {
+        let items = self.lock();
+        let mut items = self
+            .not_empty
+            .wait_while(items, |items| items.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        self.pop_front(&mut items).expect("woken with an item to pop")
+    }
+
+    /// Removes the item at the front, or returns `None` if the buffer is empty.
+    pub fn try_pop(&self) -> Option<T> {
+        self.pop_front(&mut self.lock())
+    }
+
+    /// Removes the item at the front, waiting up to `timeout` for one, or returns `None` if
+    /// the buffer is still empty.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        let items = self.lock();
+        let (mut items, _) = self
+            .not_empty
+            .wait_timeout_while(items, timeout, |items| items.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        self.pop_front(&mut items)
+    }
+
+    /// Locks the queue. A thread that panicked while holding the lock can't have left the
+    /// queue half-changed, so poisoning is ignored.
+    fn lock(&self) -> MutexGuard<'_, VecDeque<T>> {
+        self.items.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push_back(&self, items: &mut VecDeque<T>, item: T) {
+        items.push_back(item);
+        self.not_empty.notify_one();
+    }
+
+    fn pop_front(&self, items: &mut VecDeque<T>) -> Option<T> {
+        let item = items.pop_front()?;
+        self.not_full.notify_one();
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    const SHORT: Duration = Duration::from_millis(50);
+    const LONG: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn first_in_first_out() {
+        let buffer = BoundedBuffer::new(3);
+        for i in 0..3 {
+            buffer.push(i);
+        }
+        assert_eq!(buffer.len(), 3);
+        assert_eq!((0..3).map(|_| buffer.pop()).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn try_push_and_try_pop() {
+        let buffer = BoundedBuffer::new(2);
+        assert_eq!(buffer.try_pop(), None);
+        assert_eq!(buffer.try_push("a"), Ok(()));
+        assert_eq!(buffer.try_push("b"), Ok(()));
+        assert_eq!(buffer.try_push("c"), Err("c"));
+        assert_eq!(buffer.try_pop(), Some("a"));
+        assert_eq!(buffer.try_push("c"), Ok(()));
+        assert_eq!(buffer.try_pop(), Some("b"));
+        assert_eq!(buffer.try_pop(), Some("c"));
+        assert_eq!(buffer.try_pop(), None);
+    }
+
+    #[test]
+    fn timeouts_expire() {
+        let buffer = BoundedBuffer::new(1);
+        let start = Instant::now();
+        assert_eq!(buffer.pop_timeout(SHORT), None);
+        assert!(start.elapsed() >= SHORT);
+
+        buffer.push(1);
+        let start = Instant::now();
+        assert_eq!(buffer.push_timeout(2, SHORT), Err(2));
+        assert!(start.elapsed() >= SHORT);
+        assert_eq!(buffer.pop(), 1);
+    }
+
+    #[test]
+    fn timeouts_succeed() {
+        let buffer = BoundedBuffer::new(1);
+        // Ready right away.
+        assert_eq!(buffer.push_timeout(1, SHORT), Ok(()));
+        assert_eq!(buffer.pop_timeout(SHORT), Some(1));
+        // Ready once another thread gets to it.
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(SHORT);
+                buffer.push(2);
+            });
+            assert_eq!(buffer.pop_timeout(LONG), Some(2));
+        });
+        buffer.push(3);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(SHORT);
+                assert_eq!(buffer.pop(), 3);
+            });
+            assert_eq!(buffer.push_timeout(4, LONG), Ok(()));
+        });
+        assert_eq!(buffer.try_pop(), Some(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity must be at least 1")]
+    fn zero_capacity() {
+        BoundedBuffer::<i32>::new(0);
+    }
+
+    #[test]
+    fn many_producers_and_consumers() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const ITEMS: usize = 2500;
+        let buffer = BoundedBuffer::new(3);
+        let received = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let buffer = &buffer;
+                scope.spawn(move || (0..ITEMS).for_each(|i| buffer.push((producer, i))));
+            }
+            for _ in 0..CONSUMERS {
+                scope.spawn(|| {
+                    let mine: Vec<_> = (0..PRODUCERS * ITEMS / CONSUMERS).map(|_| buffer.pop()).collect();
+                    // Each consumer sees any one producer's items in the order they were pushed.
+                    for producer in 0..PRODUCERS {
+                        let order: Vec<_> = mine.iter().filter(|item| item.0 == producer).collect();
+                        assert!(order.is_sorted());
+                    }
+                    received.lock().unwrap().extend(mine);
+                });
+            }
+        });
+        let mut received = received.into_inner().unwrap();
+        received.sort_unstable();
+        let expected: Vec<_> = (0..PRODUCERS).flat_map(|p| (0..ITEMS).map(move |i| (p, i))).collect();
+        assert_eq!(received, expected);
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,20 @@
+//! Blocking synchronization primitives for the translated concurrent C programs: the bounded
+//! buffer behind prodcon, and a counting semaphore for code written against `sem_t`.
+//!
+//! ```
+//! use std::thread;
+//! use translations::sync::BoundedBuffer;
+//!
+//! let buffer = BoundedBuffer::new(7);
+//! thread::scope(|scope| {
+//!     scope.spawn(|| (0..10).for_each(|i| buffer.push(i)));
+//!     let items: Vec<i32> = (0..10).map(|_| buffer.pop()).collect();
+//!     assert_eq!(items, (0..10).collect::<Vec<_>>());
+//! });
+//! ```
+
+mod bounded_buffer;
+mod semaphore;
+
+pub use bounded_buffer::BoundedBuffer;
+pub use semaphore::{Semaphore, SemaphorePermit};
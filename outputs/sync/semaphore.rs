@@ -0,0 +1,417 @@
+use std::time::Duration;
+
+/// A counting semaphore, the Rust counterpart of POSIX `sem_t`: `acquire` is `sem_wait`,
+/// `try_acquire` is `sem_trywait`, `acquire_timeout` is `sem_timedwait` and `release` is
+/// `sem_post`.
+///
+/// Acquiring returns a [`SemaphorePermit`] that gives the permit back when dropped. Programs
+/// where one thread waits and another posts, as with a producer and consumer, call
+/// [`SemaphorePermit::forget`] and [`release`](Semaphore::release) the permit elsewhere.
+///
+/// ```
+/// use std::collections::VecDeque;
+/// use std::sync::Mutex;
+/// use std::thread;
+/// use translations::sync::Semaphore;
+///
+/// // The classic `empty`/`full` pair guarding a ring of three slots.
+/// let (empty, full) = (Semaphore::new(3), Semaphore::new(0));
+/// let slots = Mutex::new(VecDeque::new());
+/// thread::scope(|scope| {
+///     scope.spawn(|| {
+///         for i in 0..10 {
+///             empty.acquire().forget();
+///             slots.lock().unwrap().push_back(i);
+///             full.release(1);
+///         }
+///     });
+///     let mut removed = Vec::new();
+///     for _ in 0..10 {
+///         full.acquire().forget();
+///         removed.push(slots.lock().unwrap().pop_front().unwrap());
+///         empty.release(1);
+///     }
+///     assert_eq!(removed, (0..10).collect::<Vec<_>>());
+/// });
+/// assert_eq!(empty.available_permits(), 3);
+/// ```
+///
+/// Built on a `Mutex` and `Condvar`; with the `futex` feature on Linux, the count is a single
+/// atomic and only threads that have to wait make a system call.
+#[derive(Debug)]
+pub struct Semaphore {
+    counter: Counter,
+}
+
+impl Semaphore {
+    /// A semaphore holding `permits` permits, as `sem_init` would set it up.
+    ///
+    /// # Panics
+    ///
+    /// With the `futex` backend, if `permits` doesn't fit in a `u32`.
+    pub fn new(permits: usize) -> Self {
+        Semaphore { counter: Counter::new(permits) }
+    }
+
+    /// Takes a permit, waiting for one to be released if there are none.
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        self.counter.take(None);
+        SemaphorePermit { semaphore: self }
+    }
+
+    /// Takes a permit if one is available right away.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.counter.try_take().then(|| SemaphorePermit { semaphore: self })
+    }
+
+    /// Takes a permit, waiting up to `timeout` for one to be released.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Option<SemaphorePermit<'_>> {
+        self.counter.take(Some(timeout)).then(|| SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds `permits` permits, waking up to that many waiting threads.
+    ///
+    /// # Panics
+    ///
+    /// If the count would overflow: a `usize`, or a `u32` with the `futex` backend. Like
+    /// `sem_post` failing with `EOVERFLOW`, this means permits are being released that were
+    /// never taken.
+    pub fn release(&self, permits: usize) {
+        if permits > 0 {
+            self.counter.give(permits);
+        }
+    }
+
+    /// The number of permits left, which other threads may change at any moment.
+    pub fn available_permits(&self) -> usize {
+        self.counter.available()
+    }
+}
+
+/// A permit taken from a [`Semaphore`], released when dropped.
+#[derive(Debug)]
+#[must_use = "the permit is released as soon as it is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken, for a `sem_wait` whose `sem_post` happens somewhere else.
+    pub fn forget(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release(1);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "futex")))]
+use portable::Counter;
+
+#[cfg(all(target_os = "linux", feature = "futex"))]
+use futex::Counter;
+
+/// The permit count, guarded by a mutex, with a condition variable for waiting on it.
+#[cfg(not(all(target_os = "linux", feature = "futex")))]
+mod portable {
+    use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    pub(super) struct Counter {
+        permits: Mutex<usize>,
+        released: Condvar,
+    }
+
+    impl Counter {
+        pub(super) fn new(permits: usize) -> Self {
+            Counter { permits: Mutex::new(permits), released: Condvar::new() }
+        }
+
+        /// Locks the count. A count can't be left half-updated, so poisoning is ignored.
+        fn lock(&self) -> MutexGuard<'_, usize> {
+            self.permits.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub(super) fn try_take(&self) -> bool {
+            let mut permits = self.lock();
+            if *permits == 0 {
+                return false;
+            }
+            *permits -= 1;
+            true
+        }
+
+        /// Takes a permit, waiting up to `timeout` (or forever) for one. Returns whether it did.
+        pub(super) fn take(&self, timeout: Option<Duration>) -> bool {
+            let permits = self.lock();
+            let mut permits = match timeout {
+                None => self.released.wait_while(permits, |permits| *permits == 0),
+                Some(timeout) => self
+                    .released
+                    .wait_timeout_while(permits, timeout, |permits| *permits == 0)
+                    .map(|(permits, _)| permits)
+                    .map_err(|e| PoisonError::new(e.into_inner().0)),
+            }
+            .unwrap_or_else(PoisonError::into_inner);
+            if *permits == 0 {
+                return false;
+            }
+            *permits -= 1;
+            true
+        }
+
+        pub(super) fn give(&self, permits: usize) {
+            let mut count = self.lock();
+            *count = count.checked_add(permits).expect("semaphore permit count overflowed");
+            drop(count);
+            if permits == 1 {
+                self.released.notify_one();
+            } else {
+                self.released.notify_all();
+            }
+        }
+
+        pub(super) fn available(&self) -> usize {
+            *self.lock()
+        }
+    }
+}
+
+/// The permit count as one atomic word. Taking and giving permits are compare-and-swap and
+/// add; only a thread that finds no permits sleeps, on a futex on the count itself.
+#[cfg(all(target_os = "linux", feature = "futex"))]
+mod futex {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[derive(Debug)]
+    pub(super) struct Counter {
+        permits: AtomicU32,
+        /// Threads sleeping (or about to sleep) on `permits`, so `give` can skip the wake-up
+        /// system call when there are none.
+        waiters: AtomicU32,
+    }
+
+    impl Counter {
+        pub(super) fn new(permits: usize) -> Self {
+            let permits = u32::try_from(permits).expect("semaphore permits fit in a futex word");
+            Counter { permits: AtomicU32::new(permits), waiters: AtomicU32::new(0) }
+        }
+
+        pub(super) fn try_take(&self) -> bool {
+            let mut permits = self.permits.load(Ordering::Relaxed);
+            while permits > 0 {
+                let taken = permits - 1;
+                match self.permits.compare_exchange_weak(permits, taken, Ordering::Acquire, Ordering::Relaxed) {
+                    Ok(_) => return true,
+                    Err(current) => permits = current,
+                }
+            }
+            false
+        }
+
+        /// Takes a permit, waiting up to `timeout` (or forever) for one. Returns whether it did.
+        pub(super) fn take(&self, timeout: Option<Duration>) -> bool {
+            let deadline = timeout.map(|timeout| Instant::now() + timeout);
+            loop {
+                if self.try_take() {
+                    return true;
+                }
+                let remaining = match deadline {
+                    Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                        Some(remaining) if !remaining.is_zero() => Some(remaining),
+                        _ => return false,
+                    },
+                    None => None,
+                };
+                // A `give` between the failed `try_take` and the wait changes the count from 0,
+                // so the kernel returns at once instead of sleeping through it.
+                self.waiters.fetch_add(1, Ordering::SeqCst);
+                futex_wait(&self.permits, 0, remaining);
+                self.waiters.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        pub(super) fn give(&self, permits: usize) {
+            let permits = u32::try_from(permits).expect("semaphore permits fit in a futex word");
+            self.permits
+                .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |count| count.checked_add(permits))
+                .expect("semaphore permit count overflowed");
+            if self.waiters.load(Ordering::SeqCst) > 0 {
+                futex_wake(&self.permits, permits);
+            }
+        }
+
+        pub(super) fn available(&self) -> usize {
+            self.permits.load(Ordering::Relaxed) as usize
+        }
+    }
+
+    /// Sleeps while `word` holds `expected`, for up to `timeout`. Wake-ups, timeouts, signals
+    /// and a changed word all just return; the caller checks the count again either way.
+    fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+        let timespec = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        });
+        let timespec_ptr = timespec.as_ref().map_or(std::ptr::null(), |t| t as *const libc::timespec);
+        let op = libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG;
+        unsafe {
+            libc::syscall(libc::SYS_futex, word.as_ptr(), op, expected, timespec_ptr);
+        }
+    }
+
+    /// Wakes up to `count` threads sleeping on `word`.
+    fn futex_wake(word: &AtomicU32, count: u32) {
+        let count = count.min(i32::MAX as u32) as libc::c_int;
+        let op = libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG;
+        unsafe {
+            libc::syscall(libc::SYS_futex, word.as_ptr(), op, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Instant;
+
+    const SHORT: Duration = Duration::from_millis(50);
+    const LONG: Duration = Duration::from_secs(10);
+
+    /// Waits up to `LONG` for `done` to reach `count`.
+    fn wait_for(done: &AtomicUsize, count: usize) {
+        let start = Instant::now();
+        while done.load(Ordering::SeqCst) < count && start.elapsed() < LONG {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn try_acquire() {
+        let semaphore = Semaphore::new(0);
+        assert!(semaphore.try_acquire().is_none());
+        assert_eq!(semaphore.available_permits(), 0);
+
+        let semaphore = Semaphore::new(2);
+        let first = semaphore.try_acquire().unwrap();
+        let second = semaphore.try_acquire().unwrap();
+        assert!(semaphore.try_acquire().is_none());
+        drop((first, second));
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn acquire_timeout_expires() {
+        let semaphore = Semaphore::new(0);
+        let start = Instant::now();
+        assert!(semaphore.acquire_timeout(SHORT).is_none());
+        assert!(start.elapsed() >= SHORT);
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn acquire_timeout_succeeds() {
+        let semaphore = Semaphore::new(1);
+        semaphore.acquire_timeout(SHORT).unwrap().forget();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(SHORT);
+                semaphore.release(1);
+            });
+            semaphore.acquire_timeout(LONG).unwrap().forget();
+        });
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn release_wakes_that_many_waiters() {
+        let semaphore = Semaphore::new(0);
+        let acquired = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..5 {
+                scope.spawn(|| {
+                    semaphore.acquire().forget();
+                    acquired.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            thread::sleep(SHORT);
+            semaphore.release(0);
+            semaphore.release(3);
+            wait_for(&acquired, 3);
+            thread::sleep(SHORT);
+            assert_eq!(acquired.load(Ordering::SeqCst), 3);
+            semaphore.release(2);
+        });
+        assert_eq!(acquired.load(Ordering::SeqCst), 5);
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn dropped_permits_are_released() {
+        let semaphore = Semaphore::new(1);
+        {
+            let _permit = semaphore.acquire();
+            assert_eq!(semaphore.available_permits(), 0);
+        }
+        assert_eq!(semaphore.available_permits(), 1);
+        semaphore.acquire().forget();
+        assert_eq!(semaphore.available_permits(), 0);
+        semaphore.release(1);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn release_overflow() {
+        Semaphore::new(1).release(usize::MAX);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "futex"))]
+    #[test]
+    #[should_panic(expected = "semaphore permit count overflowed")]
+    fn release_overflows_futex_word() {
+        Semaphore::new(u32::MAX as usize).release(1);
+    }
+
+    #[test]
+    fn stress() {
+        // Threads take turns in a critical section guarded by a 3-permit semaphore, checking
+        // that no more than 3 are ever inside.
+        const PERMITS: usize = 3;
+        let semaphore = Semaphore::new(PERMITS);
+        let inside = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let (semaphore, inside, most) = (&semaphore, &inside, &most);
+                scope.spawn(move || {
+                    for i in 0..2000 {
+                        let permit = if (thread + i) % 3 == 0 {
+                            loop {
+                                if let Some(permit) = semaphore.acquire_timeout(Duration::from_micros(50)) {
+                                    break permit;
+                                }
+                            }
+                        } else {
+                            semaphore.acquire()
+                        };
+                        let now = inside.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        thread::yield_now();
+                        inside.fetch_sub(1, Ordering::SeqCst);
+                        drop(permit);
+                    }
+                });
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= PERMITS);
+        assert_eq!(semaphore.available_permits(), PERMITS);
+    }
+}
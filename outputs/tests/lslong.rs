@@ -0,0 +1,160 @@
+//! Runs the `lslong` binary, for what only shows in its output as a whole.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+use serde_json::Value;
+
+fn lslong(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_lslong")).args(args).output().expect("run lslong")
+}
+
+/// Runs lslong as someone permissions apply to: as `nobody` when the tests run as root, from
+/// a copy `nobody` can reach.
+fn lslong_unprivileged(args: &[&str]) -> Output {
+    if unsafe { libc::geteuid() } != 0 {
+        return lslong(args);
+    }
+    let bin = Scratch::new("bin");
+    fs::set_permissions(&bin.0, fs::Permissions::from_mode(0o755)).unwrap();
+    let copy = bin.0.join("lslong");
+    fs::copy(env!("CARGO_BIN_EXE_lslong"), &copy).unwrap();
+    Command::new(&copy).args(args).uid(65534).gid(65534).output().expect("run lslong")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// A fresh directory for one test, removed when it's dropped.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Scratch {
+        let dir = std::env::temp_dir().join(format!("lslong-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn json_is_one_array() {
+    let dir = Scratch::new("json");
+    let names = ["plain", "comma,name", "quote\"name", "new\nline", "back\\slash", "tab\tname"];
+    fs::create_dir(dir.0.join("sub")).unwrap();
+    for name in names {
+        fs::write(dir.0.join("sub").join(name), name).unwrap();
+    }
+
+    let output = lslong(&["-R", "--format=json", dir.path()]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    let records: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let mut listed: Vec<&str> = records.iter().map(|record| record["name"].as_str().unwrap()).collect();
+    listed.sort_unstable();
+    let mut expected = names.to_vec();
+    expected.push("sub");
+    expected.sort_unstable();
+    assert_eq!(listed, expected);
+    for record in records.iter().filter(|record| record["type"] == "file") {
+        assert_eq!(record["size"], record["name"].as_str().unwrap().len());
+    }
+
+    let output = lslong(&["--format=json", dir.0.join("sub").join("plain").to_str().unwrap()]);
+    let records: Vec<Value> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["size"], 5);
+
+    let empty = Scratch::new("json-empty");
+    let output = lslong(&["--format=json", empty.path()]);
+    assert_eq!(serde_json::from_slice::<Vec<Value>>(&output.stdout).unwrap(), Vec::<Value>::new());
+}
+
+#[test]
+fn success() {
+    let dir = Scratch::new("success");
+    fs::write(dir.0.join("file"), "").unwrap();
+    let output = lslong(&[dir.path()]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output).lines().nth(1), Some("total 0"));
+    assert!(stdout(&output).trim_end().ends_with(" file"));
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn missing_operands() {
+    let dir = Scratch::new("missing");
+    fs::write(dir.0.join("file"), "").unwrap();
+    let missing = dir.0.join("missing");
+    let output = lslong(&[missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("cannot access"), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+
+    // The operands that do exist are still listed.
+    let file = dir.0.join("file");
+    let output = lslong(&[missing.to_str().unwrap(), file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stdout(&output).trim_end().ends_with(file.to_str().unwrap()));
+}
+
+#[test]
+fn bad_options() {
+    for args in [&["--bogus"][..], &["--time-style=+%Q"], &["--diff", "only-one"]] {
+        let output = lslong(args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).starts_with("lslong: "), "{:?}: {}", args, stderr(&output));
+    }
+}
+
+#[test]
+fn unreadable_subdirectory() {
+    let dir = Scratch::new("unreadable");
+    // Open to `nobody`, bar the one directory.
+    fs::set_permissions(&dir.0, fs::Permissions::from_mode(0o755)).unwrap();
+    let locked = dir.0.join("locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(locked.join("hidden"), "").unwrap();
+    fs::create_dir(dir.0.join("open")).unwrap();
+    fs::write(dir.0.join("open").join("visible"), "").unwrap();
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+    let output = lslong_unprivileged(&["-R", dir.path()]);
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", stderr(&output));
+    assert!(stderr(&output).contains(locked.to_str().unwrap()), "{}", stderr(&output));
+    // Everything else is listed anyway.
+    assert!(stdout(&output).contains(" visible\n"), "{}", stdout(&output));
+    assert!(!stdout(&output).contains("hidden"));
+}
+
+#[test]
+fn directory_itself() {
+    let dir = Scratch::new("itself");
+    fs::write(dir.0.join("inside"), "").unwrap();
+    let output = lslong(&["-d", dir.path()]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert_eq!(out.lines().count(), 1, "{}", out);
+    assert!(out.starts_with('d'), "{}", out);
+    assert!(out.trim_end().ends_with(dir.path()), "{}", out);
+    assert!(!out.contains("inside"));
+}
+
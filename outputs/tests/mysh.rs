@@ -0,0 +1,81 @@
+//! Runs `mysh --record` and `--replay` as separate processes, since capturing a session
+//! redirects the whole process's stdout and stderr.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use serde_json::Value;
+
+/// `mysh --record` of a script piped to it, so that every line has its output captured.
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/mysh-session.jsonl");
+
+fn mysh(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mysh"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run mysh");
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn replay(path: &Path) -> (Option<i32>, String) {
+    let output = mysh(&["--replay", path.to_str().unwrap()], "");
+    (output.status.code(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn records(path: &Path) -> Vec<Value> {
+    let contents = fs::read_to_string(path).unwrap();
+    contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+fn scratch(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mysh-test-{}-{}.jsonl", std::process::id(), name))
+}
+
+#[test]
+fn replay_fixture() {
+    let (status, stdout) = replay(Path::new(FIXTURE));
+    assert_eq!(stdout, "replayed 9 line(s), 0 mismatch(es)\n");
+    assert_eq!(status, Some(0));
+}
+
+#[test]
+fn record_then_replay() {
+    let expected = records(Path::new(FIXTURE));
+    let inputs: Vec<&str> = expected.iter().map(|record| record["input"].as_str().unwrap()).collect();
+    let path = scratch("record");
+    let output = mysh(&["--record", path.to_str().unwrap()], &(inputs.join("\n") + "\n"));
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // The same results as the fixture, apart from timings.
+    let mut recorded = records(&path);
+    for (actual, expected) in recorded.iter().zip(&expected) {
+        for field in ["input", "commands", "status", "stdout", "stderr"] {
+            assert_eq!(actual[field], expected[field], "{} of {}", field, expected["input"]);
+        }
+    }
+    assert_eq!(recorded.len(), expected.len());
+    assert_eq!(replay(&path), (Some(0), "replayed 9 line(s), 0 mismatch(es)\n".to_string()));
+
+    // Changed results are reported, one mismatch per line.
+    recorded[1]["stdout"] = "goodbye world\n".into();
+    recorded[3]["status"] = 0.into();
+    let lines: Vec<String> = recorded.iter().map(Value::to_string).collect();
+    fs::write(&path, lines.join("\n") + "\n").unwrap();
+    let (status, stdout) = replay(&path);
+    assert_eq!(status, Some(1));
+    let reports = [
+        "line 2: `echo \"$x world\"'\n  stdout:\n  - goodbye world\n  + hello world\n",
+        "line 4: `sh -c 'echo oops >&2; exit 3''\n  status: expected 0, got 3\n",
+    ];
+    for report in reports {
+        assert!(stdout.contains(report), "{stdout}");
+    }
+    assert!(stdout.ends_with("replayed 9 line(s), 2 mismatch(es)\n"), "{stdout}");
+    fs::remove_file(path).ok();
+}
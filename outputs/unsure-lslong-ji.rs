@@ -17,24 +17,24 @@ macro_rules! expect {
 }
 
 /// Converts a given UID `uid` to a Rust string of its name, if one exists.
-fn uid_str(uid: u32) -> &'static str {
+fn uid_str(uid: u32) -> String {
     unsafe {
         let pw = libc::getpwuid(uid);
         if pw.is_null() {
-            return "Unknown";
+            return "Unknown".to_string();
         }
-        CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned().as_str()
+        CStr::from_ptr((*pw).pw_name).to_string_lossy().into_owned()
     }
 }
 
 /// Converts a given GID `gid` to a Rust string of its name, if one exists.
-fn gid_str(gid: u32) -> &'static str {
+fn gid_str(gid: u32) -> String {
     unsafe {
         let grp = libc::getgrgid(gid);
         if grp.is_null() {
-            return "Unknown";
+            return "Unknown".to_string();
         }
-        CStr::from_ptr((*grp).gr_name).to_string_lossy().into_owned().as_str()
+        CStr::from_ptr((*grp).gr_name).to_string_lossy().into_owned()
     }
 }
 